@@ -0,0 +1,193 @@
+//! Which pieces a peer has, as sent in `bitfield` messages, and how many
+//! peers of the swarm have each piece.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+
+/// Piece availability, one bit per piece with the high bit of the first
+/// byte being piece 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// No piece set out of `len`.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Every piece set out of `len`.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        bitfield.bytes.fill(0xff);
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// Wraps a `bitfield` payload as is. Its length in pieces is unknown
+    /// until checked with [`Self::for_pieces`].
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        let len = bytes.len() * 8;
+        Self { bytes, len }
+    }
+
+    /// Checks a received bitfield against the piece count of the torrent:
+    /// it must have exactly enough bytes and no spare bit set.
+    pub fn for_pieces(mut self, num_pieces: usize) -> Result<Self> {
+        if self.bytes.len() != num_pieces.div_ceil(8) {
+            bail!(
+                "bitfield of {} bytes for {} pieces",
+                self.bytes.len(),
+                num_pieces
+            );
+        }
+        self.len = num_pieces;
+        if self.iter_ones().any(|i| i >= num_pieces) {
+            bail!("bitfield has spare bits set");
+        }
+        Ok(self)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Number of pieces.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether piece `index` is set; pieces past the end are not.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Sets or clears piece `index`, which must be in range.
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(index < self.len, "piece {} out of {}", index, self.len);
+        let mask = 0x80 >> (index % 8);
+        if value {
+            self.bytes[index / 8] |= mask;
+        } else {
+            self.bytes[index / 8] &= !mask;
+        }
+    }
+
+    /// Number of pieces set.
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn all(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    /// Indices of the pieces set, in order.
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bytes.iter().enumerate().flat_map(|(i, &byte)| {
+            (0..8)
+                .filter(move |bit| byte & (0x80 >> bit) != 0)
+                .map(move |bit| i * 8 + bit)
+        })
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare = self.bytes.len() * 8 - self.len;
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= 0xff << spare;
+        }
+    }
+}
+
+/// How many connected peers have each piece.
+#[derive(Debug, Clone, Default)]
+pub struct Availability {
+    counts: Vec<u32>,
+}
+
+/// Availability shared by the connections of a torrent.
+pub type SharedAvailability = Arc<Mutex<Availability>>;
+
+impl Availability {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            counts: vec![0; num_pieces],
+        }
+    }
+
+    pub fn shared(num_pieces: usize) -> SharedAvailability {
+        Arc::new(Mutex::new(Self::new(num_pieces)))
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Number of peers having piece `index`.
+    pub fn count(&self, index: usize) -> u32 {
+        self.counts[index]
+    }
+
+    pub fn add_piece(&mut self, index: usize) {
+        self.counts[index] += 1;
+    }
+
+    pub fn add(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_ones() {
+            self.counts[index] += 1;
+        }
+    }
+
+    pub fn remove(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_ones() {
+            self.counts[index] -= 1;
+        }
+    }
+}
+
+#[test]
+fn bitfield_operations() {
+    let bitfield = Bitfield::from_bytes(vec![0b1000_0001, 0b0100_0000]);
+    assert_eq!(vec![0, 7, 9], bitfield.iter_ones().collect::<Vec<_>>());
+    let mut bitfield = bitfield.for_pieces(10).unwrap();
+    assert_eq!(3, bitfield.count_ones());
+    assert!(bitfield.get(9) && !bitfield.get(8) && !bitfield.get(10));
+    bitfield.set(8, true);
+    bitfield.set(0, false);
+    assert_eq!(vec![7, 8, 9], bitfield.iter_ones().collect::<Vec<_>>());
+
+    let full = Bitfield::full(10);
+    assert!(full.all());
+    assert_eq!(&[0xff, 0b1100_0000], full.as_bytes());
+    assert!(Bitfield::new(10).iter_ones().next().is_none());
+
+    // Spare bits set or the wrong size are protocol errors
+    assert!(Bitfield::from_bytes(vec![0, 0b0010_0000])
+        .for_pieces(10)
+        .is_err());
+    assert!(Bitfield::from_bytes(vec![0, 0, 0]).for_pieces(10).is_err());
+}
+
+#[test]
+fn availability_counts_peers() {
+    let mut availability = Availability::new(3);
+    let a = Bitfield::from_bytes(vec![0b1100_0000])
+        .for_pieces(3)
+        .unwrap();
+    availability.add(&a);
+    availability.add(&Bitfield::full(3));
+    availability.add_piece(2);
+    assert_eq!([2, 2, 2], [0, 1, 2].map(|i| availability.count(i)));
+    availability.remove(&a);
+    assert_eq!([1, 1, 2], [0, 1, 2].map(|i| availability.count(i)));
+}
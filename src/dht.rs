@@ -0,0 +1,704 @@
+//! Mainline DHT (BEP 5): KRPC over UDP for finding peers without a tracker.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::join_all;
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::BencodeValue;
+use routing::{distance, RoutingTable, K};
+
+pub use routing::{NodeId, NodeInfo};
+
+mod routing;
+
+/// Well known nodes to join the DHT through.
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Queries sent in parallel during a lookup
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Buckets untouched for this long are refreshed
+const BUCKET_REFRESH: Duration = Duration::from_secs(15 * 60);
+/// Token secrets rotate this often; tokens from the previous secret stay valid
+const SECRET_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten after this long
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Anyone can announce, so what we keep of it is bounded
+const MAX_ANNOUNCED_TORRENTS: usize = 2000;
+const MAX_PEERS_PER_TORRENT: usize = 200;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Result of a `get_peers` query to a single node.
+#[derive(Debug, Clone, Default)]
+pub struct GetPeersResponse {
+    pub token: Option<Vec<u8>>,
+    pub peers: Vec<SocketAddrV4>,
+    pub nodes: Vec<NodeInfo>,
+}
+
+struct State {
+    table: RoutingTable,
+    /// Queries awaiting a reply, with the node they were sent to
+    pending: HashMap<Vec<u8>, (SocketAddrV4, oneshot::Sender<Result<BencodeValue>>)>,
+    next_transaction: u16,
+    /// Peers announced to us, per info hash
+    peers: HashMap<[u8; 20], HashMap<SocketAddrV4, Instant>>,
+    secret: [u8; 16],
+    previous_secret: [u8; 16],
+    secret_rotated: Instant,
+}
+
+struct Inner {
+    id: NodeId,
+    socket: UdpSocket,
+    state: Mutex<State>,
+}
+
+/// A DHT node. Incoming queries are answered and the routing table maintained
+/// by background tasks that live as long as this value.
+pub struct Dht {
+    inner: Arc<Inner>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Dht {
+    /// Starts a node with a random id listening on `addr`.
+    pub async fn bind(addr: SocketAddrV4) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await.context("binding dht socket")?;
+        let id: NodeId = rand::random();
+        let inner = Arc::new(Inner {
+            id,
+            socket,
+            state: Mutex::new(State {
+                table: RoutingTable::new(id),
+                pending: HashMap::new(),
+                next_transaction: rand::random(),
+                peers: HashMap::new(),
+                secret: rand::random(),
+                previous_secret: rand::random(),
+                secret_rotated: Instant::now(),
+            }),
+        });
+
+        let receiver = tokio::spawn(inner.clone().receive_loop());
+        let maintenance = tokio::spawn(inner.clone().maintenance_loop());
+        Ok(Self {
+            inner,
+            tasks: vec![receiver, maintenance],
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// Number of nodes in the routing table.
+    pub fn node_count(&self) -> usize {
+        self.inner.state.lock().unwrap().table.len()
+    }
+
+    /// Joins the DHT through the given nodes, then looks up our own id to fill
+    /// the buckets close to us.
+    pub async fn bootstrap(&self, nodes: &[SocketAddr]) -> Result<()> {
+        let pings = nodes.iter().filter_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(self.inner.find_node(*addr, self.inner.id)),
+            SocketAddr::V6(_) => None,
+        });
+        let responded = join_all(pings).await.iter().filter(|r| r.is_ok()).count();
+        if responded == 0 {
+            bail!("no bootstrap node responded");
+        }
+        self.inner.lookup(self.inner.id, false).await;
+        Ok(())
+    }
+
+    /// Resolves host names such as [`BOOTSTRAP_NODES`] and bootstraps from them.
+    pub async fn bootstrap_hosts<S: AsRef<str>>(&self, hosts: &[S]) -> Result<()> {
+        let mut nodes = Vec::new();
+        for host in hosts {
+            match tokio::net::lookup_host(host.as_ref()).await {
+                Ok(addrs) => nodes.extend(addrs),
+                Err(e) => eprintln!("Resolving {} failed: {}", host.as_ref(), e),
+            }
+        }
+        self.bootstrap(&nodes).await
+    }
+
+    pub async fn ping(&self, addr: SocketAddrV4) -> Result<NodeId> {
+        let response = self.inner.query(addr, "ping", BTreeMap::new()).await?;
+        node_id(&response)
+    }
+
+    pub async fn find_node(&self, addr: SocketAddrV4, target: NodeId) -> Result<Vec<NodeInfo>> {
+        self.inner.find_node(addr, target).await
+    }
+
+    pub async fn get_peers(
+        &self,
+        addr: SocketAddrV4,
+        info_hash: [u8; 20],
+    ) -> Result<GetPeersResponse> {
+        self.inner.get_peers(addr, info_hash).await
+    }
+
+    pub async fn announce_peer(
+        &self,
+        addr: SocketAddrV4,
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+    ) -> Result<()> {
+        self.inner.announce_peer(addr, info_hash, port, token).await
+    }
+
+    /// Searches the DHT for peers of a torrent.
+    pub async fn find_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddrV4> {
+        self.inner.lookup(info_hash, true).await.peers
+    }
+
+    /// Searches the DHT for peers of a torrent and announces that we are
+    /// downloading it on `port` to the closest nodes.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddrV4> {
+        let lookup = self.inner.lookup(info_hash, true).await;
+        let announces = lookup.closest.iter().filter_map(|node| {
+            let token = lookup.tokens.get(&node.addr)?.clone();
+            Some(self.inner.announce_peer(node.addr, info_hash, port, token))
+        });
+        join_all(announces).await;
+        lookup.peers
+    }
+}
+
+#[derive(Default)]
+struct Lookup {
+    /// Closest nodes that answered, closest first
+    closest: Vec<NodeInfo>,
+    peers: Vec<SocketAddrV4>,
+    tokens: HashMap<SocketAddrV4, Vec<u8>>,
+}
+
+impl Inner {
+    async fn receive_loop(self: Arc<Self>) {
+        let mut buf = vec![0; 65536];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // ICMP errors from earlier sends surface here on some platforms
+                    eprintln!("DHT receive failed: {}", e);
+                    continue;
+                }
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            // Malformed packets are dropped, never fatal
+            let Ok((message, _)) = crate::decode_bencoded_value(&buf[..len]) else {
+                continue;
+            };
+            self.handle(message, from).await;
+        }
+    }
+
+    async fn handle(&self, message: BencodeValue, from: SocketAddrV4) {
+        let Some(transaction) = message.get("t").and_then(|t| t.as_bytes()) else {
+            return;
+        };
+        match message.get("y").and_then(|y| y.as_bytes()) {
+            Some(b"q") => {
+                let reply = match self.answer(&message, from) {
+                    Ok(values) => krpc(transaction, "r", "r", BencodeValue::Dict(values)),
+                    Err((code, msg)) => krpc(
+                        transaction,
+                        "e",
+                        "e",
+                        BencodeValue::List(vec![code.into(), msg.into()]),
+                    ),
+                };
+                let _ = self.socket.send_to(&reply.encode(), from).await;
+            }
+            Some(b"r") => {
+                let pending = self.state.lock().unwrap().take_pending(transaction, from);
+                if let Some(pending) = pending {
+                    let response = message.get("r").cloned().context("response without r");
+                    if let Some(id) = response.as_ref().ok().and_then(|r| node_id(r).ok()) {
+                        self.state
+                            .lock()
+                            .unwrap()
+                            .table
+                            .insert(NodeInfo { id, addr: from });
+                    }
+                    let _ = pending.send(response);
+                }
+            }
+            Some(b"e") => {
+                let pending = self.state.lock().unwrap().take_pending(transaction, from);
+                if let Some(pending) = pending {
+                    let error = message.get("e").and_then(|e| e.as_list());
+                    let code = error.and_then(|e| e.first()).and_then(|c| c.as_int());
+                    let msg = error.and_then(|e| e.get(1)).and_then(|m| m.as_str());
+                    let _ = pending.send(Err(anyhow!(
+                        "node returned error {}: {}",
+                        code.unwrap_or(0),
+                        msg.unwrap_or("")
+                    )));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Builds the reply values for a query, or a KRPC error code and message.
+    fn answer(
+        &self,
+        message: &BencodeValue,
+        from: SocketAddrV4,
+    ) -> Result<BTreeMap<Vec<u8>, BencodeValue>, (i64, &'static str)> {
+        let args = message.get("a").ok_or((203, "Missing arguments"))?;
+        let sender = args
+            .get("id")
+            .and_then(|id| id.as_bytes())
+            .and_then(|id| NodeId::try_from(id).ok())
+            .ok_or((203, "Missing or invalid id"))?;
+
+        let mut state = self.state.lock().unwrap();
+        state.table.insert(NodeInfo {
+            id: sender,
+            addr: from,
+        });
+
+        let mut values = BTreeMap::new();
+        values.insert(b"id".to_vec(), self.id.as_slice().into());
+        let hash_arg = |key| {
+            args.get(key)
+                .and_then(|v| v.as_bytes())
+                .and_then(|v| <[u8; 20]>::try_from(v).ok())
+                .ok_or((203, "Missing or invalid target"))
+        };
+        match message.get("q").and_then(|q| q.as_bytes()) {
+            Some(b"ping") => {}
+            Some(b"find_node") => {
+                let target = hash_arg("target")?;
+                let nodes = state.table.closest(&target, K);
+                values.insert(b"nodes".to_vec(), compact_nodes(&nodes).into());
+            }
+            Some(b"get_peers") => {
+                let info_hash = hash_arg("info_hash")?;
+                let token = state.token(*from.ip(), false);
+                values.insert(b"token".to_vec(), token.to_vec().into());
+                match state.peers.get(&info_hash).filter(|p| !p.is_empty()) {
+                    Some(peers) => {
+                        let peers = peers
+                            .keys()
+                            .take(50)
+                            .map(|p| BencodeValue::Bytes(compact_peer(p).to_vec()))
+                            .collect();
+                        values.insert(b"values".to_vec(), BencodeValue::List(peers));
+                    }
+                    None => {
+                        let nodes = state.table.closest(&info_hash, K);
+                        values.insert(b"nodes".to_vec(), compact_nodes(&nodes).into());
+                    }
+                }
+            }
+            Some(b"announce_peer") => {
+                let info_hash = hash_arg("info_hash")?;
+                let token = args.get("token").and_then(|t| t.as_bytes());
+                let valid = token.is_some_and(|t| {
+                    t == state.token(*from.ip(), false) || t == state.token(*from.ip(), true)
+                });
+                if !valid {
+                    return Err((203, "Bad token"));
+                }
+                let implied_port = args.get("implied_port").and_then(|p| p.as_int()) == Some(1);
+                let port = match args.get("port").and_then(|p| p.as_int()) {
+                    _ if implied_port => from.port(),
+                    Some(port) if (1..=u16::MAX as i64).contains(&port) => port as u16,
+                    _ => return Err((203, "Missing or invalid port")),
+                };
+                state.add_peer(info_hash, SocketAddrV4::new(*from.ip(), port));
+            }
+            _ => return Err((204, "Method Unknown")),
+        }
+        Ok(values)
+    }
+
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        method: &str,
+        mut args: BTreeMap<Vec<u8>, BencodeValue>,
+    ) -> Result<BencodeValue> {
+        args.insert(b"id".to_vec(), self.id.as_slice().into());
+        let (tx, rx) = oneshot::channel();
+        let transaction = {
+            let mut state = self.state.lock().unwrap();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            let transaction = state.next_transaction.to_be_bytes().to_vec();
+            state.pending.insert(transaction.clone(), (addr, tx));
+            transaction
+        };
+
+        let mut message = krpc(&transaction, "q", "a", BencodeValue::Dict(args));
+        if let BencodeValue::Dict(dict) = &mut message {
+            dict.insert(b"q".to_vec(), method.into());
+        }
+        if let Err(e) = self.socket.send_to(&message.encode(), addr).await {
+            self.state.lock().unwrap().pending.remove(&transaction);
+            return Err(e.into());
+        }
+
+        match tokio::time::timeout(QUERY_TIMEOUT, rx).await {
+            Ok(Ok(response)) => response,
+            _ => {
+                let mut state = self.state.lock().unwrap();
+                state.pending.remove(&transaction);
+                state.table.mark_failed(addr);
+                Err(anyhow!("{} timed out on {}", addr, method))
+            }
+        }
+    }
+
+    async fn find_node(&self, addr: SocketAddrV4, target: NodeId) -> Result<Vec<NodeInfo>> {
+        let mut args = BTreeMap::new();
+        args.insert(b"target".to_vec(), target.as_slice().into());
+        let response = self.query(addr, "find_node", args).await?;
+        let nodes = response
+            .get("nodes")
+            .and_then(|n| n.as_bytes())
+            .context("find_node response without nodes")?;
+        Ok(parse_compact_nodes(nodes))
+    }
+
+    async fn get_peers(&self, addr: SocketAddrV4, info_hash: [u8; 20]) -> Result<GetPeersResponse> {
+        let mut args = BTreeMap::new();
+        args.insert(b"info_hash".to_vec(), info_hash.as_slice().into());
+        let response = self.query(addr, "get_peers", args).await?;
+        let peers = response
+            .get("values")
+            .and_then(|v| v.as_list())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|p| p.as_bytes())
+            .filter_map(parse_compact_peer)
+            .collect();
+        let nodes = response
+            .get("nodes")
+            .and_then(|n| n.as_bytes())
+            .map(parse_compact_nodes)
+            .unwrap_or_default();
+        Ok(GetPeersResponse {
+            token: response
+                .get("token")
+                .and_then(|t| t.as_bytes())
+                .map(<[u8]>::to_vec),
+            peers,
+            nodes,
+        })
+    }
+
+    async fn announce_peer(
+        &self,
+        addr: SocketAddrV4,
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+    ) -> Result<()> {
+        let mut args = BTreeMap::new();
+        args.insert(b"info_hash".to_vec(), info_hash.as_slice().into());
+        args.insert(b"port".to_vec(), (port as i64).into());
+        args.insert(b"token".to_vec(), token.into());
+        self.query(addr, "announce_peer", args).await?;
+        Ok(())
+    }
+
+    /// Iterative Kademlia lookup: repeatedly queries the closest nodes not yet
+    /// asked, `ALPHA` at a time, until the `K` closest have all answered or failed.
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|n| (distance(&n.id, &target), n))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+        let mut peers = HashSet::new();
+        let mut tokens = HashMap::new();
+
+        loop {
+            let batch: Vec<NodeInfo> = candidates
+                .values()
+                .take(K)
+                .filter(|n| !queried.contains(&n.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().map(|n| n.addr));
+
+            let queries = batch.iter().map(|node| async move {
+                let response = if get_peers {
+                    self.get_peers(node.addr, target).await
+                } else {
+                    self.find_node(node.addr, target)
+                        .await
+                        .map(|nodes| GetPeersResponse {
+                            nodes,
+                            ..Default::default()
+                        })
+                };
+                (*node, response)
+            });
+            for (node, response) in join_all(queries).await {
+                let dist = distance(&node.id, &target);
+                let Ok(response) = response else {
+                    candidates.remove(&dist);
+                    continue;
+                };
+                responded.insert(dist, node);
+                peers.extend(response.peers);
+                if let Some(token) = response.token {
+                    tokens.insert(node.addr, token);
+                }
+                for found in response.nodes {
+                    if found.id != self.id && !queried.contains(&found.addr) {
+                        candidates
+                            .entry(distance(&found.id, &target))
+                            .or_insert(found);
+                    }
+                }
+            }
+        }
+
+        Lookup {
+            closest: responded.into_values().take(K).collect(),
+            peers: peers.into_iter().collect(),
+            tokens,
+        }
+    }
+
+    async fn maintenance_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let stale = {
+                let mut state = self.state.lock().unwrap();
+                if state.secret_rotated.elapsed() >= SECRET_ROTATION {
+                    state.previous_secret = state.secret;
+                    state.secret = rand::random();
+                    state.secret_rotated = Instant::now();
+                }
+                for peers in state.peers.values_mut() {
+                    peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+                }
+                state.peers.retain(|_, peers| !peers.is_empty());
+
+                let stale = state.table.stale_buckets(BUCKET_REFRESH);
+                for &index in &stale {
+                    state.table.touch(index);
+                }
+                stale
+                    .into_iter()
+                    .map(|index| state.table.random_id_in(index))
+                    .collect::<Vec<_>>()
+            };
+            for target in stale {
+                self.lookup(target, false).await;
+            }
+        }
+    }
+}
+
+impl State {
+    /// The query a reply is for, provided it comes from the node we asked:
+    /// transaction ids are short and easily guessed.
+    fn take_pending(
+        &mut self,
+        transaction: &[u8],
+        from: SocketAddrV4,
+    ) -> Option<oneshot::Sender<Result<BencodeValue>>> {
+        match self.pending.get(transaction) {
+            Some(&(addr, _)) if addr == from => self.pending.remove(transaction).map(|(_, tx)| tx),
+            _ => None,
+        }
+    }
+
+    /// Stores an announced peer. Torrents past [`MAX_ANNOUNCED_TORRENTS`]
+    /// are ignored, and past [`MAX_PEERS_PER_TORRENT`] the oldest peer of
+    /// the torrent makes room.
+    fn add_peer(&mut self, info_hash: [u8; 20], peer: SocketAddrV4) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_ANNOUNCED_TORRENTS {
+            return;
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        if !peers.contains_key(&peer) && peers.len() >= MAX_PEERS_PER_TORRENT {
+            if let Some(oldest) = peers.iter().min_by_key(|(_, &at)| at).map(|(&p, _)| p) {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(peer, Instant::now());
+    }
+
+    /// Token handed to `ip` in `get_peers` replies, proving it asked us
+    /// before it may `announce_peer`.
+    fn token(&self, ip: Ipv4Addr, previous: bool) -> [u8; 20] {
+        let secret = if previous {
+            &self.previous_secret
+        } else {
+            &self.secret
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(ip.octets());
+        hasher.finalize().into()
+    }
+}
+
+fn krpc(transaction: &[u8], kind: &str, key: &str, body: BencodeValue) -> BencodeValue {
+    let mut message = BTreeMap::new();
+    message.insert(b"t".to_vec(), transaction.into());
+    message.insert(b"y".to_vec(), kind.into());
+    message.insert(key.as_bytes().to_vec(), body);
+    BencodeValue::Dict(message)
+}
+
+fn node_id(response: &BencodeValue) -> Result<NodeId> {
+    response
+        .get("id")
+        .and_then(|id| id.as_bytes())
+        .and_then(|id| NodeId::try_from(id).ok())
+        .context("response without a valid node id")
+}
+
+fn compact_peer(peer: &SocketAddrV4) -> [u8; 6] {
+    let mut compact = [0; 6];
+    compact[..4].copy_from_slice(&peer.ip().octets());
+    compact[4..].copy_from_slice(&peer.port().to_be_bytes());
+    compact
+}
+
+fn parse_compact_peer(compact: &[u8]) -> Option<SocketAddrV4> {
+    let compact: [u8; 6] = compact.try_into().ok()?;
+    Some(SocketAddrV4::new(
+        Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]),
+        u16::from_be_bytes([compact[4], compact[5]]),
+    ))
+}
+
+fn compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    nodes
+        .iter()
+        .flat_map(|n| n.id.into_iter().chain(compact_peer(&n.addr)))
+        .collect()
+}
+
+fn parse_compact_nodes(compact: &[u8]) -> Vec<NodeInfo> {
+    compact
+        .chunks_exact(26)
+        .filter_map(|c| {
+            Some(NodeInfo {
+                id: c[..20].try_into().ok()?,
+                addr: parse_compact_peer(&c[20..])?,
+            })
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn nodes_find_announced_peers() {
+    let localhost = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+    let mut nodes = Vec::new();
+    for _ in 0..5 {
+        nodes.push(Dht::bind(localhost).await.unwrap());
+    }
+    let router = nodes[0].local_addr().unwrap();
+    for node in &nodes[1..] {
+        node.bootstrap(&[router]).await.unwrap();
+    }
+    assert!(nodes[1].node_count() >= 2);
+
+    let SocketAddr::V4(first) = nodes[1].local_addr().unwrap() else {
+        unreachable!()
+    };
+    assert_eq!(nodes[1].id(), nodes[2].ping(first).await.unwrap());
+
+    let info_hash = [42; 20];
+    assert!(nodes[3].announce(info_hash, 6881).await.is_empty());
+    let peers = nodes[4].find_peers(info_hash).await;
+    assert_eq!(vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881)], peers);
+}
+
+#[tokio::test]
+async fn rejects_announce_with_bad_token() {
+    let localhost = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+    let a = Dht::bind(localhost).await.unwrap();
+    let b = Dht::bind(localhost).await.unwrap();
+    let SocketAddr::V4(addr) = a.local_addr().unwrap() else {
+        unreachable!()
+    };
+
+    let response = b.get_peers(addr, [1; 20]).await.unwrap();
+    let token = response.token.unwrap();
+    assert!(b.announce_peer(addr, [1; 20], 1, vec![0; 4]).await.is_err());
+    b.announce_peer(addr, [1; 20], 1, token).await.unwrap();
+    let response = b.get_peers(addr, [1; 20]).await.unwrap();
+    assert_eq!(
+        vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1)],
+        response.peers
+    );
+}
+
+#[tokio::test]
+async fn ignores_replies_from_other_nodes_and_bounds_peers() {
+    let dht = Dht::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut state = dht.inner.state.lock().unwrap();
+    let asked = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1000);
+    let (tx, _rx) = oneshot::channel();
+    state.pending.insert(vec![0, 1], (asked, tx));
+    let spoofed = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1001);
+    assert!(state.take_pending(&[0, 1], spoofed).is_none());
+    assert!(state.take_pending(&[0, 1], asked).is_some());
+
+    for port in 1..=MAX_PEERS_PER_TORRENT as u16 + 10 {
+        state.add_peer([1; 20], SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    }
+    assert_eq!(MAX_PEERS_PER_TORRENT, state.peers[&[1; 20]].len());
+    for i in 0..MAX_ANNOUNCED_TORRENTS + 10 {
+        let mut info_hash = [0; 20];
+        info_hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        state.add_peer(info_hash, asked);
+    }
+    assert_eq!(MAX_ANNOUNCED_TORRENTS, state.peers.len());
+}
@@ -0,0 +1,193 @@
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+/// Nodes per bucket
+pub const K: usize = 8;
+/// Nodes that failed this many queries in a row are replaced first.
+const MAX_FAILURES: u32 = 2;
+
+pub type NodeId = [u8; 20];
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0; 20];
+    for (i, byte) in d.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    d
+}
+
+/// A node's contact information, as carried in "compact node info".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    info: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    nodes: Vec<Node>,
+    last_changed: Instant,
+}
+
+/// Kademlia routing table. Bucket `i` holds the nodes whose id shares exactly
+/// `i` leading bits with ours, so nearby nodes are known in more detail.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        let bucket = Bucket {
+            nodes: Vec::new(),
+            last_changed: Instant::now(),
+        };
+        Self {
+            own_id,
+            buckets: vec![bucket; 160],
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let d = distance(&self.own_id, id);
+        let zeros = d
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| i * 8 + d[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+
+    /// Records that a node is alive. A full bucket only takes the node if it
+    /// can evict one that keeps failing. Returns whether the node is in the table.
+    pub fn insert(&mut self, info: NodeInfo) -> bool {
+        let Some(index) = self.bucket_index(&info.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        let now = Instant::now();
+        if let Some(node) = bucket.nodes.iter_mut().find(|n| n.info.id == info.id) {
+            node.info.addr = info.addr;
+            node.last_seen = now;
+            node.failures = 0;
+            bucket.last_changed = now;
+            return true;
+        }
+        let node = Node {
+            info,
+            last_seen: now,
+            failures: 0,
+        };
+        if bucket.nodes.len() < K {
+            bucket.nodes.push(node);
+        } else if let Some(bad) = bucket.nodes.iter_mut().find(|n| n.failures >= MAX_FAILURES) {
+            *bad = node;
+        } else {
+            return false;
+        }
+        bucket.last_changed = now;
+        true
+    }
+
+    /// Counts a query the node at `addr` didn't answer.
+    pub fn mark_failed(&mut self, addr: SocketAddrV4) {
+        for bucket in self.buckets.iter_mut() {
+            if let Some(node) = bucket.nodes.iter_mut().find(|n| n.info.addr == addr) {
+                node.failures += 1;
+            }
+        }
+    }
+
+    /// The `count` known nodes closest to `target`, closest first.
+    /// Nodes that keep failing are left out.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flat_map(|b| b.nodes.iter())
+            .filter(|n| n.failures < MAX_FAILURES)
+            .map(|n| n.info)
+            .collect();
+        nodes.sort_by_key(|n| distance(&n.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    /// Buckets that have nodes but haven't changed for `age`.
+    /// Searching a random id in them (see [`Self::random_id_in`]) refreshes them.
+    pub fn stale_buckets(&self, age: Duration) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| !b.nodes.is_empty() && b.last_changed.elapsed() >= age)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Marks a bucket as refreshed, so it isn't reported stale again right away.
+    pub fn touch(&mut self, index: usize) {
+        self.buckets[index].last_changed = Instant::now();
+    }
+
+    /// A random id that falls into bucket `index`.
+    pub fn random_id_in(&self, index: usize) -> NodeId {
+        let mut id: NodeId = rand::random();
+        let (byte, bit) = (index / 8, index % 8);
+        id[..byte].copy_from_slice(&self.own_id[..byte]);
+        // Keep our prefix bits, flip bit `index`, randomize the rest
+        let prefix_mask = !(0xffu8 >> bit);
+        let flip = 0x80u8 >> bit;
+        id[byte] = (self.own_id[byte] & prefix_mask)
+            | (!self.own_id[byte] & flip)
+            | (id[byte] & !(prefix_mask | flip));
+        id
+    }
+}
+
+#[test]
+fn bucket_layout() {
+    let own = [0; 20];
+    let mut table = RoutingTable::new(own);
+    let node = |first: u8, n: u8| NodeInfo {
+        id: {
+            let mut id = [0; 20];
+            id[0] = first;
+            id[19] = n;
+            id
+        },
+        addr: SocketAddrV4::new([127, 0, 0, 1].into(), n as u16),
+    };
+
+    assert!(!table.insert(NodeInfo {
+        id: own,
+        ..node(0, 0)
+    }));
+    for n in 0..K as u8 {
+        assert!(table.insert(node(0x80, n)));
+    }
+    // Bucket 0 is full of good nodes
+    assert!(!table.insert(node(0x80, 100)));
+    table.mark_failed(node(0x80, 3).addr);
+    table.mark_failed(node(0x80, 3).addr);
+    assert!(table.insert(node(0x80, 100)));
+    assert_eq!(K, table.len());
+
+    assert!(table.insert(node(0x01, 1)));
+    assert_eq!(vec![node(0x01, 1), node(0x80, 0)], table.closest(&own, 2));
+
+    for index in [0, 7, 8, 159] {
+        let id = table.random_id_in(index);
+        assert_eq!(Some(index), table.bucket_index(&id));
+    }
+}
@@ -0,0 +1,245 @@
+//! Extension protocol (BEP 10): the extended handshake and the routing of
+//! extended messages to the handlers of the extensions we support.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+
+use crate::BencodeValue;
+
+/// Extended message id of the extended handshake itself
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The bencoded dictionary exchanged as extended message 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message id the sender wants to receive
+    /// them with. Id 0 means the extension is disabled.
+    pub m: BTreeMap<String, u8>,
+    /// Client name and version
+    pub v: Option<String>,
+    /// Our TCP listen port
+    pub p: Option<u16>,
+    /// How many outstanding requests the sender accepts
+    pub reqq: Option<u32>,
+    /// Size of the info dictionary, for `ut_metadata` (BEP 9)
+    pub metadata_size: Option<usize>,
+    /// The receiver's IP address as seen by the sender
+    pub yourip: Option<IpAddr>,
+}
+
+impl ExtendedHandshake {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        let m = self
+            .m
+            .iter()
+            .map(|(name, &id)| (name.as_bytes().to_vec(), (id as i64).into()))
+            .collect();
+        dict.insert(b"m".to_vec(), BencodeValue::Dict(m));
+        if let Some(v) = &self.v {
+            dict.insert(b"v".to_vec(), v.as_str().into());
+        }
+        if let Some(p) = self.p {
+            dict.insert(b"p".to_vec(), (p as i64).into());
+        }
+        if let Some(reqq) = self.reqq {
+            dict.insert(b"reqq".to_vec(), (reqq as i64).into());
+        }
+        if let Some(size) = self.metadata_size {
+            dict.insert(b"metadata_size".to_vec(), (size as i64).into());
+        }
+        if let Some(ip) = self.yourip {
+            let ip = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            dict.insert(b"yourip".to_vec(), ip.into());
+        }
+        BencodeValue::Dict(dict).encode()
+    }
+
+    /// Parses a remote handshake. Keys with unexpected types or values are
+    /// ignored rather than failing the connection.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (dict, _) = crate::decode_bencoded_value(bytes)?;
+        dict.as_dict()
+            .context("extended handshake is not a dictionary")?;
+        let int = |key| dict.get(key).and_then(|v| v.as_int());
+
+        let m = dict
+            .get("m")
+            .and_then(|m| m.as_dict())
+            .map(|m| {
+                m.iter()
+                    .filter_map(|(name, id)| {
+                        let name = String::from_utf8(name.clone()).ok()?;
+                        let id = u8::try_from(id.as_int()?).ok()?;
+                        Some((name, id))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let yourip = dict
+            .get("yourip")
+            .and_then(|ip| ip.as_bytes())
+            .and_then(|ip| match ip.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?)),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?)),
+                _ => None,
+            });
+        Ok(Self {
+            m,
+            v: dict.get("v").and_then(|v| v.as_str()).map(str::to_string),
+            p: int("p").and_then(|p| u16::try_from(p).ok()),
+            reqq: int("reqq").and_then(|r| u32::try_from(r).ok()),
+            metadata_size: int("metadata_size").and_then(|s| usize::try_from(s).ok()),
+            yourip,
+        })
+    }
+}
+
+/// Handles the messages of one extension, such as `ut_metadata` or `ut_pex`.
+pub trait ExtensionHandler: Send {
+    /// The name the extension is registered under in `m`.
+    fn name(&self) -> &'static str;
+
+    /// Adds extension specific keys to our handshake before it is sent.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called when the remote's handshake arrives, and again if it sends a new one.
+    fn on_handshake(&mut self, _remote: &ExtendedHandshake) {}
+
+    /// Handles a message of this extension. Replies are queued in `replies`
+    /// and sent back with the id the remote assigned to this extension.
+    fn on_message(&mut self, payload: &[u8], replies: &mut Vec<Vec<u8>>) -> Result<()>;
+}
+
+/// The extensions enabled on a connection. The extension registered n-th
+/// receives messages with local id n (starting at 1).
+#[derive(Default)]
+pub struct Extensions {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    remote: Option<ExtendedHandshake>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables an extension, returning the id the remote must use to send its messages.
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) -> u8 {
+        self.handlers.push(handler);
+        self.handlers.len() as u8
+    }
+
+    /// Our handshake: `base` with every registered extension in `m`.
+    pub fn handshake(&self, mut base: ExtendedHandshake) -> ExtendedHandshake {
+        for (i, handler) in self.handlers.iter().enumerate() {
+            base.m.insert(handler.name().to_string(), i as u8 + 1);
+            handler.extend_handshake(&mut base);
+        }
+        base
+    }
+
+    /// The remote's handshake, once received.
+    pub fn remote(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// The id to send messages of extension `name` with, if the remote supports it.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote
+            .as_ref()?
+            .m
+            .get(name)
+            .copied()
+            .filter(|&id| id != 0)
+    }
+
+    /// Routes the payload of an extended message (id byte first) to its handler.
+    /// Returns the replies to send, already prefixed with the remote's id.
+    /// Messages for extensions we never registered are ignored.
+    pub fn dispatch(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (&id, body) = payload.split_first().context("empty extended message")?;
+        if id == HANDSHAKE_ID {
+            let remote = ExtendedHandshake::from_bytes(body)?;
+            for handler in self.handlers.iter_mut() {
+                handler.on_handshake(&remote);
+            }
+            self.remote = Some(remote);
+            return Ok(Vec::new());
+        }
+
+        let Some(handler) = self.handlers.get_mut(id as usize - 1) else {
+            return Ok(Vec::new());
+        };
+        let mut replies = Vec::new();
+        handler.on_message(body, &mut replies)?;
+        let name = handler.name();
+        let Some(remote_id) = self.remote_id(name) else {
+            return Ok(Vec::new());
+        };
+        Ok(replies
+            .into_iter()
+            .map(|reply| {
+                let mut message = vec![remote_id];
+                message.extend(reply);
+                message
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn handshake_round_trip() {
+    let handshake = ExtendedHandshake {
+        m: [("ut_metadata".to_string(), 3), ("ut_pex".to_string(), 0)].into(),
+        v: Some("torrust 0.1.0".to_string()),
+        p: Some(6881),
+        reqq: Some(250),
+        metadata_size: Some(31235),
+        yourip: Some("10.0.0.1".parse().unwrap()),
+    };
+    assert_eq!(
+        handshake,
+        ExtendedHandshake::from_bytes(&handshake.to_bytes()).unwrap()
+    );
+    // Bogus values are skipped, not fatal
+    let lenient = ExtendedHandshake::from_bytes(b"d1:md1:ai300ee1:pi-1e6:youripi1ee").unwrap();
+    assert_eq!(ExtendedHandshake::default(), lenient);
+}
+
+#[test]
+fn dispatches_by_negotiated_id() {
+    struct Echo;
+    impl ExtensionHandler for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+        fn on_message(&mut self, payload: &[u8], replies: &mut Vec<Vec<u8>>) -> Result<()> {
+            replies.push(payload.to_vec());
+            Ok(())
+        }
+    }
+
+    let mut extensions = Extensions::new();
+    assert_eq!(1, extensions.register(Box::new(Echo)));
+    assert_eq!(
+        Some(&1),
+        extensions.handshake(Default::default()).m.get("echo")
+    );
+
+    // No reply before the remote told us its id for the extension
+    assert!(extensions.dispatch(b"\x01hi").unwrap().is_empty());
+    extensions.dispatch(b"\x00d1:md4:echoi7eee").unwrap();
+    assert_eq!(Some(7), extensions.remote_id("echo"));
+    assert_eq!(
+        vec![b"\x07hi".to_vec()],
+        extensions.dispatch(b"\x01hi").unwrap()
+    );
+    // Unknown local ids are ignored
+    assert!(extensions.dispatch(b"\x09hi").unwrap().is_empty());
+}
@@ -1,95 +1,539 @@
+pub mod bitfield;
+pub mod dht;
+pub mod extension;
+pub mod lsd;
+pub mod magnet;
+pub mod metadata;
 pub mod peer;
+pub mod pex;
+pub mod picker;
+pub mod storage;
 pub mod torrent;
 pub mod tracker;
 pub mod worker;
 
+use std::collections::BTreeMap;
+use std::fmt;
+
 use serde_json::{self, Value};
+use thiserror::Error;
+
+/// Nesting limit for lists and dictionaries, so hostile input can't blow the stack.
+const MAX_DEPTH: usize = 256;
+
+/// A decoded bencode value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<BencodeValue>),
+    Dict(BTreeMap<Vec<u8>, BencodeValue>),
+}
 
-pub fn decode_bencoded_value(encoded_value: &str) -> (Value, &str) {
-    match encoded_value.chars().next() {
-        // Number encoded
-        Some('i') => {
-            if let Some((n, rest)) =
-                encoded_value
-                    .split_at(1)
-                    .1
-                    .split_once('e')
-                    .and_then(|(digits, rest)| {
-                        let n = digits.parse::<i64>().ok()?;
-                        Some((n, rest))
-                    })
-            {
-                return (n.into(), rest);
+/// Error returned when the input is not valid bencode.
+/// `offset` is the position of the offending byte from the start of the input.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid bencode at byte {offset}: expected {expected}, found {}", Found(*.found))]
+pub struct BencodeError {
+    pub offset: usize,
+    pub expected: &'static str,
+    pub found: Option<u8>,
+}
+
+struct Found(Option<u8>);
+
+impl fmt::Display for Found {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(b) if b.is_ascii_graphic() => write!(f, "'{}'", b as char),
+            Some(b) => write!(f, "byte {:#04x}", b),
+            None => f.write_str("end of input"),
+        }
+    }
+}
+
+/// Decodes the first bencode value of `encoded_value`,
+/// returning it together with the remaining bytes.
+pub fn decode_bencoded_value(encoded_value: &[u8]) -> Result<(BencodeValue, &[u8]), BencodeError> {
+    let mut decoder = Decoder {
+        input: encoded_value,
+        pos: 0,
+    };
+    let value = decoder.value(0)?;
+    Ok((value, &encoded_value[decoder.pos..]))
+}
+
+/// Finds `key` in the top-level dictionary of `encoded_value` and returns the exact
+/// bytes of its value as they appear in the input, without re-encoding them.
+pub fn raw_dict_value<'a>(
+    encoded_value: &'a [u8],
+    key: &[u8],
+) -> Result<Option<&'a [u8]>, BencodeError> {
+    let mut decoder = Decoder {
+        input: encoded_value,
+        pos: 0,
+    };
+    decoder.expect(b'd', "'d'")?;
+    while decoder.peek() != Some(b'e') {
+        match decoder.peek() {
+            Some(c) if c.is_ascii_digit() => {}
+            _ => return Err(decoder.error("byte string key or 'e'")),
+        }
+        let k = decoder.bytes()?;
+        let start = decoder.pos;
+        decoder.value(1)?;
+        if k == key {
+            return Ok(Some(&encoded_value[start..decoder.pos]));
+        }
+    }
+    Ok(None)
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, expected: &'static str) -> BencodeError {
+        BencodeError {
+            offset: self.pos,
+            expected,
+            found: self.peek(),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8, expected: &'static str) -> Result<(), BencodeError> {
+        if self.peek() != Some(byte) {
+            return Err(self.error(expected));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<BencodeValue, BencodeError> {
+        match self.peek() {
+            // Number encoded
+            Some(b'i') => self.integer().map(BencodeValue::Integer),
+            // List encoded
+            Some(b'l') => {
+                if depth >= MAX_DEPTH {
+                    return Err(self.error("less deeply nested value"));
+                }
+                self.pos += 1;
+                let mut elems = Vec::new();
+                while self.peek() != Some(b'e') {
+                    if self.peek().is_none() {
+                        return Err(self.error("list element or 'e'"));
+                    }
+                    elems.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(BencodeValue::List(elems))
+            }
+            // Dictionary encoded
+            Some(b'd') => {
+                if depth >= MAX_DEPTH {
+                    return Err(self.error("less deeply nested value"));
+                }
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek() != Some(b'e') {
+                    match self.peek() {
+                        Some(c) if c.is_ascii_digit() => {}
+                        _ => return Err(self.error("byte string key or 'e'")),
+                    }
+                    let k = self.bytes()?;
+                    let v = self.value(depth + 1)?;
+                    dict.insert(k, v);
+                }
+                self.pos += 1;
+                Ok(BencodeValue::Dict(dict))
             }
+            // String encoded
+            Some(c) if c.is_ascii_digit() => self.bytes().map(BencodeValue::Bytes),
+            _ => Err(self.error("'i', 'l', 'd' or a string length")),
         }
-        // List encoded
-        Some('l') => {
-            let mut elems = Vec::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && !rest.starts_with('e') {
-                let (e, reminder) = decode_bencoded_value(rest);
-                elems.push(e);
-                rest = reminder;
+    }
+
+    /// Reads the digits up to `terminator`, rejecting leading zeros.
+    fn digits(&mut self, terminator: u8) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = &self.input[start..self.pos];
+        if digits.is_empty() {
+            return Err(self.error("a digit"));
+        }
+        if digits.len() > 1 && digits[0] == b'0' {
+            return Err(BencodeError {
+                offset: start,
+                expected: "a number without leading zeros",
+                found: Some(b'0'),
+            });
+        }
+        if self.peek() != Some(terminator) {
+            return Err(self.error(if terminator == b'e' { "'e'" } else { "':'" }));
+        }
+        Ok(digits)
+    }
+
+    fn integer(&mut self) -> Result<i64, BencodeError> {
+        self.expect(b'i', "'i'")?;
+        let negative = self.peek() == Some(b'-');
+        if negative {
+            self.pos += 1;
+        }
+        let start = self.pos;
+        let digits = self.digits(b'e')?;
+        if negative && digits == b"0" {
+            return Err(BencodeError {
+                offset: start,
+                expected: "a non-zero negative number",
+                found: Some(b'0'),
+            });
+        }
+        // Digits are ASCII, so this can only fail on overflow
+        let n = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|d| d.parse::<i64>().ok())
+            .map(|n| if negative { -n } else { n })
+            .ok_or(BencodeError {
+                offset: start,
+                expected: "a number that fits in 64 bits",
+                found: digits.first().copied(),
+            })?;
+        self.pos += 1;
+        Ok(n)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, BencodeError> {
+        let start = self.pos;
+        let digits = self.digits(b':')?;
+        let len = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|d| d.parse::<usize>().ok())
+            .ok_or(BencodeError {
+                offset: start,
+                expected: "a string length that fits in memory",
+                found: digits.first().copied(),
+            })?;
+        self.pos += 1;
+        if self.input.len() - self.pos < len {
+            return Err(BencodeError {
+                offset: self.input.len(),
+                expected: "more string bytes",
+                found: None,
+            });
+        }
+        let bytes = self.input[self.pos..self.pos + len].to_vec();
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+impl BencodeValue {
+    /// Canonical encoding: dictionary keys are emitted in sorted byte order,
+    /// so `decode(encode(v)) == v` and canonical input re-encodes byte for byte.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    pub fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            BencodeValue::Integer(n) => {
+                buf.push(b'i');
+                buf.extend_from_slice(n.to_string().as_bytes());
+                buf.push(b'e');
+            }
+            BencodeValue::Bytes(b) => encode_bytes(b, buf),
+            BencodeValue::List(l) => {
+                buf.push(b'l');
+                for v in l {
+                    v.encode_to(buf);
+                }
+                buf.push(b'e');
             }
-            return (elems.into(), &rest[1..]);
-        }
-        // List encoded
-        Some('d') => {
-            let mut dict = serde_json::Map::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && !rest.starts_with('e') {
-                let (k, reminder) = decode_bencoded_value(rest);
-                let k = match k {
-                    Value::String(k) => k,
-                    _ => panic!("invalid key"),
-                };
-                let (v, reminder) = decode_bencoded_value(reminder);
-                dict.insert(k.to_string(), v);
-                rest = reminder;
+            BencodeValue::Dict(d) => {
+                buf.push(b'd');
+                for (k, v) in d {
+                    encode_bytes(k, buf);
+                    v.encode_to(buf);
+                }
+                buf.push(b'e');
             }
-            return (dict.into(), &rest[1..]);
         }
-        // String encoded
-        Some(c) if c.is_ascii_digit() => {
-            if let Some((len, rest)) = encoded_value.split_once(':') {
-                let len = len.parse::<usize>().unwrap();
-                return (rest[..len].to_string().into(), &rest[len..]);
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// The byte string as UTF-8 text, if it is valid.
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this is a dictionary.
+    pub fn get(&self, key: &str) -> Option<&BencodeValue> {
+        self.as_dict().and_then(|d| d.get(key.as_bytes()))
+    }
+
+    /// Converts to JSON. Byte strings become JSON strings: valid UTF-8 is kept
+    /// as is, while `%` and every byte that is not part of valid UTF-8 are written
+    /// as `%XX` (uppercase hex), so the conversion is reversible with [`Self::from_json`].
+    pub fn to_json(&self) -> Value {
+        match self {
+            BencodeValue::Integer(n) => (*n).into(),
+            BencodeValue::Bytes(b) => escape_bytes(b).into(),
+            BencodeValue::List(l) => l.iter().map(BencodeValue::to_json).collect(),
+            BencodeValue::Dict(d) => d
+                .iter()
+                .map(|(k, v)| (escape_bytes(k), v.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+
+    /// Inverse of [`Self::to_json`]. Fails on JSON types bencode can't represent
+    /// (null, booleans, non-integer numbers) and on malformed `%` escapes.
+    pub fn from_json(value: &Value) -> Result<Self, JsonConversionError> {
+        Ok(match value {
+            Value::Null => return Err(JsonConversionError::Unsupported("null")),
+            Value::Bool(_) => return Err(JsonConversionError::Unsupported("boolean")),
+            Value::Number(n) => BencodeValue::Integer(n.as_i64().ok_or(
+                JsonConversionError::Unsupported("non-integer or 64-bit overflowing number"),
+            )?),
+            Value::String(s) => BencodeValue::Bytes(unescape_bytes(s)?),
+            Value::Array(a) => BencodeValue::List(
+                a.iter()
+                    .map(BencodeValue::from_json)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(o) => BencodeValue::Dict(
+                o.iter()
+                    .map(|(k, v)| Ok((unescape_bytes(k)?, BencodeValue::from_json(v)?)))
+                    .collect::<Result<_, JsonConversionError>>()?,
+            ),
+        })
+    }
+}
+
+impl From<i64> for BencodeValue {
+    fn from(n: i64) -> Self {
+        BencodeValue::Integer(n)
+    }
+}
+
+impl From<&str> for BencodeValue {
+    fn from(s: &str) -> Self {
+        BencodeValue::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for BencodeValue {
+    fn from(b: &[u8]) -> Self {
+        BencodeValue::Bytes(b.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(b: Vec<u8>) -> Self {
+        BencodeValue::Bytes(b)
+    }
+}
+
+/// Error returned by [`BencodeValue::from_json`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum JsonConversionError {
+    #[error("bencode has no equivalent for JSON {0}")]
+    Unsupported(&'static str),
+    #[error("invalid percent escape in {0:?}")]
+    InvalidEscape(String),
+}
+
+fn encode_bytes(b: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(b.len().to_string().as_bytes());
+    buf.push(b':');
+    buf.extend_from_slice(b);
+}
+
+fn escape_bytes(mut b: &[u8]) -> String {
+    let mut escaped = String::with_capacity(b.len());
+    loop {
+        let (valid, invalid) = match std::str::from_utf8(b) {
+            Ok(valid) => (valid, &[][..]),
+            Err(e) => {
+                let (valid, rest) = b.split_at(e.valid_up_to());
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                b = &rest[invalid_len..];
+                // `valid_up_to` guarantees this prefix is UTF-8
+                (
+                    std::str::from_utf8(valid).expect("valid prefix"),
+                    &rest[..invalid_len],
+                )
+            }
+        };
+        for c in valid.chars() {
+            match c {
+                '%' => escaped.push_str("%25"),
+                c => escaped.push(c),
             }
         }
-        _ => {}
+        for byte in invalid {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+        if invalid.is_empty() {
+            return escaped;
+        }
     }
-    panic!("Unhandled encoded value: {}", encoded_value)
+}
+
+fn unescape_bytes(s: &str) -> Result<Vec<u8>, JsonConversionError> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(byte) = iter.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = [iter.next(), iter.next()];
+        let decoded = match hex {
+            [Some(h), Some(l)] => hex::decode([h, l]).ok(),
+            _ => None,
+        };
+        match decoded {
+            Some(decoded) => bytes.extend(decoded),
+            None => return Err(JsonConversionError::InvalidEscape(s.to_string())),
+        }
+    }
+    Ok(bytes)
 }
 
 #[test]
 fn decode_str() {
-    let encoded = "4:hola";
-    let decoded = decode_bencoded_value(encoded);
-    assert_eq!(Value::String("hola".to_string()), decoded.0);
+    let encoded = b"4:hola";
+    let decoded = decode_bencoded_value(encoded).unwrap();
+    assert_eq!(BencodeValue::Bytes(b"hola".to_vec()), decoded.0);
 }
 
 #[test]
 fn decode_number() {
-    let encoded = "i52e";
-    let decoded = decode_bencoded_value(encoded);
-    assert_eq!(Value::Number(52.into()), decoded.0);
+    let encoded = b"i52e";
+    let decoded = decode_bencoded_value(encoded).unwrap();
+    assert_eq!(BencodeValue::Integer(52), decoded.0);
 }
 
 #[test]
 fn decode_list() {
-    let encoded = "li52e4:holae";
-    let decoded = decode_bencoded_value(encoded);
-    let expec = Value::Array(vec![
-        Value::Number(52.into()),
-        Value::String("hola".to_string()),
+    let encoded = b"li52e4:holae";
+    let decoded = decode_bencoded_value(encoded).unwrap();
+    let expec = BencodeValue::List(vec![
+        BencodeValue::Integer(52),
+        BencodeValue::Bytes(b"hola".to_vec()),
     ]);
     assert_eq!(expec, decoded.0);
 }
 
 #[test]
 fn decode_dict() {
-    let encoded = "d3:foo3:bar5:helloi52ee";
-    let decoded = decode_bencoded_value(encoded);
+    let encoded = b"d3:foo3:bar5:helloi52ee";
+    let decoded = decode_bencoded_value(encoded).unwrap();
     let expec = serde_json::json!({"foo":"bar", "hello": 52});
-    assert_eq!(expec, decoded.0);
+    assert_eq!(expec, decoded.0.to_json());
+}
+
+#[test]
+fn decode_binary_and_rest() {
+    let encoded = b"3:\xff\x00\x01i-7e";
+    let (value, rest) = decode_bencoded_value(encoded).unwrap();
+    assert_eq!(BencodeValue::Bytes(vec![0xff, 0x00, 0x01]), value);
+    assert_eq!(b"i-7e", rest);
+}
+
+#[test]
+fn decode_malformed() {
+    let cases: &[(&[u8], usize)] = &[
+        (b"", 0),
+        (b"x", 0),
+        (b"i12", 3),
+        (b"ie", 1),
+        (b"i-0e", 2),
+        (b"i03e", 1),
+        (b"i99999999999999999999e", 1),
+        (b"5:abc", 5),
+        (b"l4:hola", 7),
+        (b"di1e3:fooe", 1),
+        (b"d3:fooe", 6),
+    ];
+    for (encoded, offset) in cases {
+        let err = decode_bencoded_value(encoded).unwrap_err();
+        assert_eq!(err.offset, *offset, "{:?}: {}", encoded, err);
+    }
+    let nested = [b'l'; MAX_DEPTH + 1];
+    assert!(decode_bencoded_value(&nested).is_err());
+}
+
+#[test]
+fn encode_canonical_round_trip() {
+    let encoded: &[u8] = b"d3:bar3:\xff%\x003:fooi52e4:listli-3ei0e0:ee";
+    let (value, rest) = decode_bencoded_value(encoded).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(encoded, &value.encode()[..]);
+}
+
+#[test]
+fn encode_sorts_keys() {
+    let (value, _) = decode_bencoded_value(b"d1:bi1e1:ai2ee").unwrap();
+    assert_eq!(b"d1:ai2e1:bi1ee", &value.encode()[..]);
+}
+
+#[test]
+fn json_round_trip() {
+    let (value, _) =
+        decode_bencoded_value(b"d2:\xfe\xff3:a%b1:ll5:h\xc3\xa9lo1:\x80i-1eee").unwrap();
+    let json = value.to_json();
+    assert_eq!(
+        serde_json::json!({"%FE%FF": "a%25b", "l": ["h\u{e9}lo", "%80", -1]}),
+        json
+    );
+    assert_eq!(value, BencodeValue::from_json(&json).unwrap());
+}
+
+#[test]
+fn json_unsupported() {
+    assert!(BencodeValue::from_json(&serde_json::json!(1.5)).is_err());
+    assert!(BencodeValue::from_json(&serde_json::json!([null])).is_err());
+    assert!(BencodeValue::from_json(&serde_json::json!("%4")).is_err());
 }
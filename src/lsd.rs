@@ -0,0 +1,339 @@
+//! Local Service Discovery (BEP 14): finds peers on the local network by
+//! multicasting `BT-SEARCH` announces.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+use crate::torrent::Torrent;
+
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+/// Every torrent is announced this often
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// We never announce more often than this, and ignore a peer announcing the
+/// same torrent again sooner
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Keeps each datagram well under the usual MTU
+const MAX_HASHES_PER_ANNOUNCE: usize = 20;
+/// Pause after a failed receive, so a broken socket doesn't spin
+const RECEIVE_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A `BT-SEARCH` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    /// Port the announcing client accepts peer connections on
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Lets a client recognise its own announces when they loop back
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn to_bytes(&self, group: SocketAddr) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            group, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message += &format!("Infohash: {}\r\n", hex::encode(info_hash));
+        }
+        if let Some(cookie) = &self.cookie {
+            message += &format!("cookie: {}\r\n", cookie);
+        }
+        message += "\r\n\r\n";
+        message.into_bytes()
+    }
+
+    /// Parses an announce, ignoring unknown headers and malformed info hashes.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut lines = text.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    if let Some(info_hash) = hex::decode(value)
+                        .ok()
+                        .and_then(|h| <[u8; 20]>::try_from(h).ok())
+                    {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            port: port.filter(|&p| p != 0)?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Where to send the peers found for each torrent
+    torrents: HashMap<[u8; 20], mpsc::UnboundedSender<SocketAddr>>,
+    last_announce: Option<Instant>,
+    /// When each peer's announce of each torrent was last forwarded
+    seen: HashMap<(IpAddr, [u8; 20]), Instant>,
+}
+
+impl State {
+    /// Forwards the peer behind an announce to the torrents we have, unless
+    /// it is one of ours or the peer announced the torrent too recently.
+    fn on_announce(&mut self, from: SocketAddr, announce: &Announce, cookie: &str) {
+        if announce.cookie.as_deref() == Some(cookie) {
+            return;
+        }
+        if self.seen.len() > 1024 {
+            self.seen
+                .retain(|_, at| at.elapsed() < MIN_ANNOUNCE_INTERVAL);
+        }
+
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        for info_hash in &announce.info_hashes {
+            let Some(peers) = self.torrents.get(info_hash) else {
+                continue;
+            };
+            let key = (from.ip(), *info_hash);
+            if self
+                .seen
+                .get(&key)
+                .is_some_and(|at| at.elapsed() < MIN_ANNOUNCE_INTERVAL)
+            {
+                continue;
+            }
+            self.seen.insert(key, Instant::now());
+            let _ = peers.send(peer);
+        }
+    }
+
+    /// Info hashes still worth announcing, forgetting torrents nobody listens for anymore.
+    fn active(&mut self) -> Vec<[u8; 20]> {
+        self.torrents.retain(|_, peers| !peers.is_closed());
+        self.torrents.keys().copied().collect()
+    }
+}
+
+struct Inner {
+    /// Port we accept peer connections on
+    port: u16,
+    cookie: String,
+    /// Sockets joined to a multicast group, with the group address
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    state: Mutex<State>,
+    announce_now: Notify,
+}
+
+/// Local service discovery for the torrents added to it. Announces and
+/// incoming announces are handled by background tasks that live as long as this value.
+pub struct Lsd {
+    inner: Arc<Inner>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Lsd {
+    /// Joins the BEP 14 multicast groups, announcing that we accept peers on `port`.
+    /// IPv6 is best effort, IPv4 has to work.
+    pub fn bind(port: u16) -> Result<Self> {
+        let mut sockets = vec![(
+            multicast_socket(IpAddr::V4(LSD_GROUP_V4)).context("joining the LSD IPv4 group")?,
+            SocketAddr::from((LSD_GROUP_V4, LSD_PORT)),
+        )];
+        match multicast_socket(IpAddr::V6(LSD_GROUP_V6)) {
+            Ok(socket) => sockets.push((socket, SocketAddr::from((LSD_GROUP_V6, LSD_PORT)))),
+            Err(e) => eprintln!("LSD over IPv6 disabled: {:#}", e),
+        }
+
+        let inner = Arc::new(Inner {
+            port,
+            cookie: hex::encode(rand::random::<[u8; 8]>()),
+            sockets,
+            state: Mutex::new(State::default()),
+            announce_now: Notify::new(),
+        });
+        let mut tasks: Vec<_> = (0..inner.sockets.len())
+            .map(|i| tokio::spawn(inner.clone().receive_loop(i)))
+            .collect();
+        tasks.push(tokio::spawn(inner.clone().announce_loop()));
+        Ok(Self { inner, tasks })
+    }
+
+    /// Starts announcing a torrent and sends the local peers found for it to
+    /// `peers`, until that channel closes. Private torrents are ignored (BEP 27).
+    pub fn add_torrent(&self, torrent: &Torrent, peers: mpsc::UnboundedSender<SocketAddr>) {
+        if torrent.info.is_private() {
+            return;
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        state.torrents.insert(torrent.info_hash(), peers);
+        self.inner.announce_now.notify_one();
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.inner.state.lock().unwrap().torrents.remove(info_hash);
+    }
+}
+
+impl Inner {
+    async fn receive_loop(self: Arc<Self>, socket: usize) {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (len, from) = match self.sockets[socket].0.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(_) => {
+                    tokio::time::sleep(RECEIVE_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            if let Some(announce) = Announce::parse(&buf[..len]) {
+                let mut state = self.state.lock().unwrap();
+                state.on_announce(from, &announce, &self.cookie);
+            }
+        }
+    }
+
+    async fn announce_loop(self: Arc<Self>) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(ANNOUNCE_INTERVAL) => {}
+                _ = self.announce_now.notified() => {}
+            }
+            let last = self.state.lock().unwrap().last_announce;
+            if let Some(wait) = last.and_then(|at| MIN_ANNOUNCE_INTERVAL.checked_sub(at.elapsed()))
+            {
+                tokio::time::sleep(wait).await;
+            }
+
+            let info_hashes = {
+                let mut state = self.state.lock().unwrap();
+                state.last_announce = Some(Instant::now());
+                state.active()
+            };
+            for chunk in info_hashes.chunks(MAX_HASHES_PER_ANNOUNCE) {
+                let announce = Announce {
+                    port: self.port,
+                    info_hashes: chunk.to_vec(),
+                    cookie: Some(self.cookie.clone()),
+                };
+                for (socket, group) in &self.sockets {
+                    let _ = socket.send_to(&announce.to_bytes(*group), group).await;
+                }
+            }
+        }
+    }
+}
+
+/// A socket on the LSD port joined to `group`, shared with the other
+/// clients on this host.
+fn multicast_socket(group: IpAddr) -> Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(SocketAddr::new(group, 0)),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    match group {
+        IpAddr::V4(group) => {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        IpAddr::V6(group) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, LSD_PORT)).into())?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[test]
+fn announce_round_trip() {
+    let announce = Announce {
+        port: 6881,
+        info_hashes: vec![[0xab; 20], [0x01; 20]],
+        cookie: Some(String::from("c00k1e")),
+    };
+    let bytes = announce.to_bytes(SocketAddr::from((LSD_GROUP_V4, LSD_PORT)));
+    assert!(bytes.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+    assert_eq!(Some(announce), Announce::parse(&bytes));
+
+    let lenient = b"BT-SEARCH * HTTP/1.1\r\nHOST: [ff15::efc0:988f]:6771\r\nport: 1234\r\nInfohash: ABABABABABABABABABABABABABABABABABABABAB\r\nInfohash: nope\r\n\r\n\r\n";
+    let parsed = Announce::parse(lenient).unwrap();
+    assert_eq!(1234, parsed.port);
+    assert_eq!(vec![[0xab; 20]], parsed.info_hashes);
+    assert!(Announce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_none());
+}
+
+#[test]
+fn announces_are_filtered_and_rate_limited() {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut state = State::default();
+    state.torrents.insert([1; 20], tx);
+    let from: SocketAddr = "192.168.1.7:6771".parse().unwrap();
+    let announce = |info_hash, cookie: Option<&str>| Announce {
+        port: 51413,
+        info_hashes: vec![info_hash],
+        cookie: cookie.map(String::from),
+    };
+
+    state.on_announce(from, &announce([1; 20], Some("ours")), "ours");
+    state.on_announce(from, &announce([2; 20], None), "ours");
+    assert!(rx.try_recv().is_err());
+
+    state.on_announce(from, &announce([1; 20], Some("theirs")), "ours");
+    assert_eq!(Ok("192.168.1.7:51413".parse().unwrap()), rx.try_recv());
+    state.on_announce(from, &announce([1; 20], None), "ours");
+    assert!(rx.try_recv().is_err());
+
+    // Announces dropped meanwhile don't push the next one further away
+    let forwarded = Instant::now() - MIN_ANNOUNCE_INTERVAL;
+    state.seen.insert((from.ip(), [1; 20]), forwarded);
+    state.on_announce(from, &announce([1; 20], None), "ours");
+    assert_eq!(Ok("192.168.1.7:51413".parse().unwrap()), rx.try_recv());
+    state
+        .seen
+        .insert((from.ip(), [1; 20]), forwarded + Duration::from_secs(1));
+    state.on_announce(from, &announce([1; 20], None), "ours");
+    assert!(rx.try_recv().is_err());
+    assert_eq!(
+        forwarded + Duration::from_secs(1),
+        state.seen[&(from.ip(), [1; 20])]
+    );
+}
@@ -0,0 +1,160 @@
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+
+use anyhow::{bail, Context, Result};
+use reqwest::Url;
+
+/// A parsed `magnet:` URI (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    /// From `xt=urn:btih:`, given as 40 hex or 32 base32 characters
+    pub info_hash: [u8; 20],
+    /// `dn`: a name to show until the metadata is known
+    pub display_name: Option<String>,
+    /// `tr`: tracker URLs
+    pub trackers: Vec<String>,
+    /// `x.pe`: peers to connect to directly
+    pub peers: Vec<SocketAddr>,
+    /// `so`: ranges of indices of the files to download (BEP 53), empty
+    /// meaning all. Kept as given, see [`Self::selected_files`].
+    pub select_only: Vec<RangeInclusive<usize>>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri).context("parsing magnet link")?;
+        if url.scheme() != "magnet" {
+            bail!("not a magnet link: {}", uri);
+        }
+
+        let mut info_hash = None;
+        let mut magnet = Magnet {
+            info_hash: [0; 20],
+            display_name: None,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        };
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    // Other hash types (btmh, ed2k, ...) may come alongside
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => magnet.display_name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                // Host names are not resolved, so only IP peers are kept
+                "x.pe" => {
+                    if let Ok(peer) = value.parse() {
+                        magnet.peers.push(peer);
+                    }
+                }
+                "so" => magnet.select_only = parse_select_only(&value)?,
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash.context("magnet link has no urn:btih exact topic")?;
+        Ok(magnet)
+    }
+
+    /// The indices of the files to download once the torrent is known to
+    /// have `num_files`, in order and without duplicates.
+    pub fn selected_files(&self, num_files: usize) -> Vec<usize> {
+        if self.select_only.is_empty() {
+            return (0..num_files).collect();
+        }
+        (0..num_files)
+            .filter(|file| self.select_only.iter().any(|range| range.contains(file)))
+            .collect()
+    }
+}
+
+fn parse_info_hash(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("invalid hex info hash")?,
+        32 => base32_decode(hash).context("invalid base32 info hash")?,
+        _ => bail!("info hash must be 40 hex or 32 base32 characters"),
+    };
+    Ok(bytes.try_into().expect("20 bytes"))
+}
+
+/// RFC 4648 base32 without padding, as used for info hashes.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// Parses a list like `0,2,4,6-8`. Ranges aren't expanded, the link may
+/// come from anyone.
+fn parse_select_only(value: &str) -> Result<Vec<RangeInclusive<usize>>> {
+    let mut ranges = Vec::new();
+    for part in value.split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end): (usize, usize) = (start.parse()?, end.parse()?);
+                if end < start {
+                    bail!("invalid file range {}", part);
+                }
+                ranges.push(start..=end);
+            }
+            None => {
+                let index = part.parse()?;
+                ranges.push(index..=index);
+            }
+        }
+    }
+    Ok(ranges)
+}
+
+#[test]
+fn parses_magnet_links() {
+    let magnet = Magnet::parse(
+        "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif\
+         &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+         &x.pe=10.0.0.1:6881&x.pe=peer.example:6881&so=0,2-3",
+    )
+    .unwrap();
+    assert_eq!(
+        "ad42ce8109f54c99613ce38f9b4d87e70f24a165",
+        hex::encode(magnet.info_hash)
+    );
+    assert_eq!(Some("magnet1.gif".to_string()), magnet.display_name);
+    assert_eq!(
+        vec!["http://bittorrent-test-tracker.codecrafters.io/announce".to_string()],
+        magnet.trackers
+    );
+    assert_eq!(
+        vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()],
+        magnet.peers
+    );
+    assert_eq!(vec![0..=0, 2..=3], magnet.select_only);
+    assert_eq!(vec![0, 2], magnet.selected_files(3));
+
+    let huge = Magnet::parse(
+        "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&so=1,0-4000000000",
+    )
+    .unwrap();
+    assert_eq!(vec![0, 1, 2], huge.selected_files(3));
+
+    let base32 = Magnet::parse("magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF").unwrap();
+    assert_eq!(magnet.info_hash, base32.info_hash);
+
+    assert!(Magnet::parse("magnet:?dn=nothing").is_err());
+    assert!(Magnet::parse("magnet:?xt=urn:btih:abc").is_err());
+}
@@ -1,15 +1,30 @@
-use anyhow::{self, Context, Result};
-use torrust::peer::{self, *};
-use torrust::torrent::Torrent;
-use torrust::tracker::{self, TrackerRequest, TrackerResponse};
+use anyhow::{self, bail, Context, Result};
 use clap::{Parser, Subcommand};
-use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Write;
-use std::net::SocketAddrV4;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::PathBuf;
-
-const BLOCK_MAX: u32 = 16384;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use torrust::bitfield::{Availability, SharedAvailability};
+use torrust::dht::{self, Dht};
+use torrust::extension::ExtendedHandshake;
+use torrust::lsd::Lsd;
+use torrust::magnet::Magnet;
+use torrust::metadata;
+use torrust::peer::*;
+use torrust::storage::Storage;
+use torrust::torrent::{Keys, Torrent};
+use torrust::tracker::{
+    self, AnnounceConfig, AnnounceTask, Announcer, TrackerRequest, TransferStats,
+};
+use torrust::worker::{self, Pipeline, Worker};
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a peer has to tell us it has the piece we want
+const PIECES_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about=None)]
@@ -41,9 +56,16 @@ enum Commands {
         piece_index: usize,
     },
     Download {
+        /// The file of a single-file torrent, the directory to create the
+        /// torrent's own directory in otherwise
         #[arg(short)]
         output: PathBuf,
-        torrent: PathBuf,
+        /// A .torrent file or a magnet link
+        torrent: String,
+    },
+    Scrape {
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
     },
 }
 
@@ -52,21 +74,35 @@ async fn main() -> Result<()> {
     let args = Args::parse();
     match args.command {
         Commands::Decode { value } => {
-            let decoded_value = torrust::decode_bencoded_value(&value);
-            println!("{}", decoded_value.0);
+            let (decoded_value, _) = torrust::decode_bencoded_value(value.as_bytes())?;
+            println!("{}", decoded_value.to_json());
         }
 
         Commands::Info { torrent } => {
             let torrent = read_torrent(torrent)?;
-            let info_hash = torrent.info_hash()?;
-            println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.info.length);
+            let info_hash = torrent.info_hash();
+            if let Some(announce) = &torrent.announce {
+                println!("Tracker URL: {}", announce);
+            }
+            println!("Trackers:");
+            for (tier, urls) in torrent.trackers().iter().enumerate() {
+                for url in urls {
+                    println!("  tier {tier}: {url}");
+                }
+            }
+            println!("Length: {}", torrent.info.length());
             println!("Info Hash: {}", hex::encode(info_hash));
             println!("Piece Length: {}", torrent.info.plength);
             println!("Piece Hashes:");
-            for piece in torrent.info.pieces.0 {
+            for piece in &torrent.info.pieces.0 {
                 println!("{}", hex::encode(piece));
             }
+            if let Keys::MultiFile { .. } = torrent.info.keys {
+                println!("Files:");
+                for file in torrent.info.files() {
+                    println!("{} ({} bytes)", file.path.display(), file.length);
+                }
+            }
         }
         Commands::Peers { torrent } => {
             let torrent = read_torrent(torrent)?;
@@ -78,12 +114,13 @@ async fn main() -> Result<()> {
         }
         Commands::Handshake { torrent, peer } => {
             let torrent = read_torrent(torrent)?;
-            let info_hash = torrent.info_hash()?;
+            let info_hash = torrent.info_hash();
 
-            let peer = peer.parse::<SocketAddrV4>()?;
+            let peer = peer.parse::<SocketAddr>()?;
             let peer = Peer::connect_peer(peer, info_hash).await?;
 
             println!("Peer ID: {}", hex::encode(peer.peer_id));
+            println!("Capabilities: {}", peer.capabilities());
         }
         Commands::DownloadPiece {
             output,
@@ -95,162 +132,334 @@ async fn main() -> Result<()> {
         Commands::Download { output, torrent } => {
             download(torrent, output).await?;
         }
+        Commands::Scrape { torrents } => {
+            scrape(torrents).await?;
+        }
     }
     Ok(())
 }
 
 fn read_torrent(torrent: PathBuf) -> Result<Torrent> {
     let file = fs::read(torrent)?;
-    Ok(serde_bencode::from_bytes::<Torrent>(&file)?)
+    Torrent::from_bytes(&file)
+}
+
+/// Asks the trackers for peers, falling back to the DHT when there are no
+/// trackers or none of them answers.
+async fn get_peers(torrent: &Torrent) -> Result<Vec<SocketAddr>> {
+    match tracker_peers(
+        torrent.trackers(),
+        torrent.info_hash(),
+        torrent.info.length(),
+    )
+    .await
+    {
+        Ok(peers) if !peers.is_empty() || torrent.info.is_private() => return Ok(peers),
+        Err(e) if torrent.info.is_private() => return Err(e),
+        Ok(_) => eprintln!("Trackers returned no peers, searching the DHT"),
+        Err(e) => eprintln!("{:#}, searching the DHT", e),
+    }
+    let dht = bind_dht().await?;
+    dht.bootstrap_hosts(&torrent.dht_bootstrap_nodes()).await?;
+    let peers = dht.find_peers(torrent.info_hash()).await;
+    Ok(peers.into_iter().map(SocketAddr::from).collect())
 }
 
-async fn get_peers(torrent: &Torrent) -> Result<Vec<SocketAddrV4>> {
-    let info_hash = torrent.info_hash()?;
+/// Binds the DHT to the default port, or any port when that one is taken,
+/// by another client or another DHT of ours.
+async fn bind_dht() -> Result<Dht> {
+    match Dht::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 6881)).await {
+        Ok(dht) => Ok(dht),
+        Err(_) => Dht::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await,
+    }
+}
+
+/// Keeps announcing the torrent on the DHT and forwards the peers found,
+/// until the receiving side goes away.
+fn spawn_dht(
+    info_hash: [u8; 20],
+    bootstrap: Vec<String>,
+    peers: mpsc::UnboundedSender<SocketAddr>,
+) {
+    tokio::spawn(async move {
+        let dht = match bind_dht().await {
+            Ok(dht) => dht,
+            Err(e) => return eprintln!("DHT disabled: {:#}", e),
+        };
+        if let Err(e) = dht.bootstrap_hosts(&bootstrap).await {
+            return eprintln!("DHT bootstrap failed: {:#}", e);
+        }
+        loop {
+            for peer in dht.announce(info_hash, 6881).await {
+                let _ = peers.send(peer.into());
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(5 * 60)) => {}
+                _ = peers.closed() => break,
+            }
+        }
+    });
+}
+
+/// Loads a `.torrent` file, or resolves a magnet link by fetching the info
+/// dictionary from peers found through its trackers, its `x.pe` peers and the DHT.
+async fn load_torrent(source: &str) -> Result<Torrent> {
+    if !source.starts_with("magnet:") {
+        return read_torrent(source.into());
+    }
+    let magnet = Magnet::parse(source)?;
+    let info_hash = magnet.info_hash;
+    if let Some(name) = &magnet.display_name {
+        eprintln!("Fetching metadata for {}", name);
+    }
+
+    let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+    for &peer in &magnet.peers {
+        let _ = peers_tx.send(peer);
+    }
+    let bootstrap = dht::BOOTSTRAP_NODES.iter().map(|n| n.to_string()).collect();
+    spawn_dht(info_hash, bootstrap, peers_tx.clone());
+    if !magnet.trackers.is_empty() {
+        let trackers = magnet.trackers.iter().map(|t| vec![t.clone()]).collect();
+        tokio::spawn(async move {
+            // The length is unknown until we have the metadata
+            match tracker_peers(trackers, info_hash, 1).await {
+                Ok(peers) => peers.into_iter().for_each(|p| {
+                    let _ = peers_tx.send(p);
+                }),
+                Err(e) => eprintln!("{:#}", e),
+            }
+        });
+    }
+
+    let mut tried = HashSet::new();
+    while let Some(peer) = peers_rx.recv().await {
+        if !tried.insert(peer) {
+            continue;
+        }
+        let fetch =
+            tokio::time::timeout(METADATA_TIMEOUT, metadata::fetch_metadata(peer, info_hash));
+        match fetch.await {
+            Ok(Ok(info_bytes)) => return Torrent::from_info_bytes(&info_bytes, &magnet.trackers),
+            Ok(Err(e)) => eprintln!("Metadata from {} failed: {:#}", peer, e),
+            Err(_) => eprintln!("Metadata from {} timed out", peer),
+        }
+    }
+    bail!("no peer sent the metadata")
+}
+
+async fn tracker_peers(
+    trackers: Vec<Vec<String>>,
+    info_hash: [u8; 20],
+    left: usize,
+) -> Result<Vec<SocketAddr>> {
     let tracker_request = TrackerRequest {
         peer_id: String::from("00112233445566778899"),
         port: 6881,
         uploaded: 0,
         downloaded: 0,
-        left: torrent.info.length,
+        left,
         compact: 1,
+        event: None,
+        numwant: None,
+        key: None,
+        trackerid: None,
     };
 
-    let query = serde_urlencoded::to_string(&tracker_request)?;
-    let url = format!(
-        "{}?{}&info_hash={}",
-        torrent.announce,
-        query,
-        tracker::hash_encoder(&info_hash)
-    );
-    let response = reqwest::get(url).await?;
-    let response = response.bytes().await?;
-    let response: TrackerResponse = serde_bencode::from_bytes(&response)?;
-    Ok(response.peers.0)
+    let mut announcer = Announcer::new(trackers);
+    let response = announcer.announce(&info_hash, &tracker_request).await?;
+    if let Some(warning) = response.warning {
+        eprintln!("Tracker warning: {}", warning);
+    }
+    Ok(response.peers)
 }
 
-async fn download_piece(torrent: PathBuf, output: PathBuf, piece_index: usize) -> Result<()> {
-    let torrent = read_torrent(torrent)?;
-    let info_hash = torrent.info_hash()?;
-    assert!(piece_index < torrent.info.pieces.0.len());
-
-    let peers = get_peers(&torrent).await?;
-    let peer_address = peers[0];
-
-    let mut peer = Peer::connect_peer(peer_address, info_hash).await?;
-
-    let msg_bitfield = peer.read_message().await?;
-    // Bitfield has to be th first message always
-    assert_eq!(msg_bitfield.tag, MessageTag::Bitfield);
-    eprintln!("Got bitfield");
-
-    // Send interested
-    peer.send_message(Message {
-        tag: MessageTag::Interested,
-        payload: Vec::new(),
-    })
-    .await?;
-    eprintln!("sent interested");
-
-    // Await for unchoke
-    let msg_unchocked = peer.read_message().await?;
-    // Bitfield has to be th first message always
-    assert_eq!(msg_unchocked.tag, MessageTag::Unchoke);
-    assert!(msg_unchocked.payload.is_empty());
-    eprintln!("got unchocked");
+/// Prints swarm health, batching the torrents that share a primary tracker
+/// into a single scrape.
+async fn scrape(paths: Vec<PathBuf>) -> Result<()> {
+    let mut by_tracker: HashMap<String, Vec<(PathBuf, Torrent)>> = HashMap::new();
+    for path in paths {
+        let torrent = read_torrent(path.clone())?;
+        match torrent.trackers().first().and_then(|tier| tier.first()) {
+            Some(url) => by_tracker
+                .entry(url.clone())
+                .or_default()
+                .push((path, torrent)),
+            None => println!("{}: no tracker", path.display()),
+        }
+    }
 
-    // Request a piece by blocks
-    request_piece(&torrent, piece_index, &mut peer, output).await?;
+    for (url, torrents) in by_tracker {
+        let info_hashes: Vec<[u8; 20]> = torrents.iter().map(|(_, t)| t.info_hash()).collect();
+        let stats = match tracker::scrape(&url, &info_hashes).await {
+            Ok(stats) => stats,
+            Err(e) => {
+                for (path, _) in &torrents {
+                    println!("{}: scrape failed: {:#}", path.display(), e);
+                }
+                continue;
+            }
+        };
+        for (path, torrent) in &torrents {
+            match stats.get(&torrent.info_hash()) {
+                Some(s) => println!(
+                    "{}: {} seeders, {} leechers, {} completed ({})",
+                    path.display(),
+                    s.complete,
+                    s.incomplete,
+                    s.downloaded,
+                    url
+                ),
+                None => println!("{}: unknown to {}", path.display(), url),
+            }
+        }
+    }
     Ok(())
 }
 
-async fn download(torrent: PathBuf, output: PathBuf) -> Result<()> {
+async fn download_piece(torrent: PathBuf, output: PathBuf, piece_index: usize) -> Result<()> {
     let torrent = read_torrent(torrent)?;
-    let info_hash = torrent.info_hash()?;
+    let info_hash = torrent.info_hash();
+    let num_pieces = torrent.info.num_pieces();
+    if piece_index >= num_pieces {
+        bail!(
+            "piece {} out of range, the torrent has {}",
+            piece_index,
+            num_pieces
+        );
+    }
 
     let peers = get_peers(&torrent).await?;
-    // TODO: Use the worker module to add each peer to allow the download of each part simultaneously
-    let peer_address = peers[0];
-
-    let mut peer = Peer::connect_peer(peer_address, info_hash).await?;
-
-    let msg_bitfield = peer.read_message().await?;
-    // Bitfield has to be th first message always
-    assert_eq!(msg_bitfield.tag, MessageTag::Bitfield);
-    eprintln!("Got bitfield");
-
-    // Send interested
-    peer.send_message(Message {
-        tag: MessageTag::Interested,
-        payload: Vec::new(),
-    })
-    .await?;
-    eprintln!("sent interested");
-
-    // Await for unchoke
-    let msg_unchocked = peer.read_message().await?;
-    // Bitfield has to be th first message always
-    assert_eq!(msg_unchocked.tag, MessageTag::Unchoke);
-    assert!(msg_unchocked.payload.is_empty());
-    eprintln!("got unchocked");
-
-    let mut pieces: Vec<u8> = Vec::with_capacity(torrent.info.length);
-    for piece_index in 0..torrent.info.pieces.0.len() {
-        let path = format!("{}-part-{piece_index}", output.to_str().unwrap()).into();
-        let piece = request_piece(&torrent, piece_index, &mut peer, path).await?;
-        pieces.extend(piece);
+    let availability = Availability::shared(num_pieces);
+    // A corrupt piece is discarded and asked from the next peer
+    let mut piece = None;
+    for address in peers {
+        let mut peer = match peer_with_piece(address, info_hash, &availability, piece_index).await {
+            Ok(Some(peer)) => peer,
+            Ok(None) => {
+                eprintln!("{} does not have piece {}", address, piece_index);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("{}: {:#}", address, e);
+                continue;
+            }
+        };
+        match piece_from(&torrent, &mut peer, piece_index).await {
+            Ok(data) => {
+                piece = Some(data);
+                break;
+            }
+            Err(e) => eprintln!("{}: {:#}", address, e),
+        }
     }
+    let piece = piece.with_context(|| format!("no peer sent piece {}", piece_index))?;
 
     let mut file = fs::File::create(output).context("Creating output file failed")?;
-    file.write_all(&pieces)
+    file.write_all(&piece)
         .context("Writing to output file failed")?;
     file.flush().context("Output file flush failed")?;
-
     Ok(())
 }
 
-async fn request_piece(
-    torrent: &Torrent,
-    piece_index: usize,
-    peer: &mut Peer,
-    output: PathBuf,
-) -> Result<Vec<u8>> {
-    let piece_hash = &torrent.info.pieces.0[piece_index];
-    let piece_size =
-        (torrent.info.plength).min(torrent.info.length - torrent.info.plength * piece_index);
+/// Downloads a piece from a peer that has it.
+async fn piece_from(torrent: &Torrent, peer: &mut Peer, piece_index: usize) -> Result<Vec<u8>> {
+    // Whatever the peer says first, we only need to wait until we may request
+    peer.send_message(PeerMessage::Interested).await?;
 
-    let mut blocks: Vec<u8> = Vec::with_capacity(piece_size);
+    // Request a piece by blocks, again whenever the peer chokes us midway
+    let mut pipeline = Pipeline::new();
     loop {
-        let block_size = BLOCK_MAX.min((piece_size - blocks.len()) as u32);
-        let mut request = Request::new(piece_index as u32, blocks.len() as u32, block_size);
+        while !worker::may_request(peer, piece_index) {
+            peer.read_message().await?;
+        }
+        let info = &torrent.info;
+        if let Some(piece) = worker::request_piece(info, piece_index, peer, &mut pipeline).await? {
+            return Ok(piece);
+        }
+    }
+}
 
-        peer.send_message(Message {
-            tag: MessageTag::Request,
-            payload: Vec::from(peer::as_bytes_mut(&mut request)),
+/// Connects to a peer and waits for it to tell us which pieces it has.
+/// `None` if piece `index` isn't among them.
+async fn peer_with_piece(
+    address: SocketAddr,
+    info_hash: [u8; 20],
+    availability: &SharedAvailability,
+    index: usize,
+) -> Result<Option<Peer>> {
+    let mut peer = Peer::connect_peer(address, info_hash).await?;
+    peer.track_pieces(availability.clone());
+    if peer.supports_extensions() {
+        peer.send_extended_handshake(ExtendedHandshake {
+            p: Some(6881),
+            ..Default::default()
         })
         .await?;
-
-        // Waits for a piece
-        let piece_msg = peer.read_message().await?;
-        assert_eq!(piece_msg.tag, MessageTag::Piece);
-        assert!(!piece_msg.payload.is_empty());
-
-        let piece = Piece::from_u8(&piece_msg.payload[..])?;
-        assert_eq!(piece.block().len(), block_size as usize);
-        blocks.extend(piece.block());
-        if blocks.len() >= piece_size {
-            break;
+    }
+    // The bitfield, if any, comes first; lazy peers follow with haves
+    let learn = async {
+        while !peer.has_piece(index) {
+            peer.read_message().await?;
         }
+        anyhow::Ok(())
+    };
+    match tokio::time::timeout(PIECES_TIMEOUT, learn).await {
+        Ok(result) => result.map(|_| Some(peer)),
+        Err(_) => Ok(None),
     }
+}
 
-    assert_eq!(blocks.len(), piece_size);
-    let mut hasher = Sha1::new();
-    hasher.update(&blocks);
-    let hash: [u8; 20] = hasher.finalize().try_into()?;
-    assert_eq!(&hash, piece_hash);
+async fn download(torrent: String, output: PathBuf) -> Result<()> {
+    let torrent = load_torrent(&torrent).await?;
+    let info_hash = torrent.info_hash();
+
+    let stats = Arc::new(TransferStats::new(torrent.info.length()));
+    let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+    let private = torrent.info.is_private();
+    // Kept alive for the whole download
+    let _lsd = if private {
+        None
+    } else {
+        spawn_dht(info_hash, torrent.dht_bootstrap_nodes(), peers_tx.clone());
+        match Lsd::bind(6881) {
+            Ok(lsd) => {
+                lsd.add_torrent(&torrent, peers_tx.clone());
+                Some(lsd)
+            }
+            Err(e) => {
+                eprintln!("Local service discovery disabled: {:#}", e);
+                None
+            }
+        }
+    };
+    let announce = (!torrent.trackers().is_empty()).then(|| {
+        AnnounceTask::spawn(
+            Announcer::new(torrent.trackers()),
+            info_hash,
+            AnnounceConfig {
+                peer_id: String::from("00112233445566778899"),
+                port: 6881,
+                numwant: Some(50),
+            },
+            stats.clone(),
+            peers_tx.clone(),
+        )
+    });
+
+    let storage = Storage::create(&torrent.info, &output)?;
+    let (pieces_tx, mut pieces_rx) = mpsc::channel(16);
+    let worker = Worker::new(Arc::new(torrent));
+    let worker = tokio::spawn(worker.run(peers_rx, pieces_tx));
+    while let Some((piece_index, piece)) = pieces_rx.recv().await {
+        storage.write_piece(piece_index, &piece)?;
+        stats.add_downloaded(piece.len());
+    }
+    worker.await??;
 
-    let mut file = fs::File::create(output).context("Creating output file failed")?;
-    file.write_all(&blocks)
-        .context("Writing to output file failed")?;
-    file.flush().context("Output file flush failed")?;
-    Ok(blocks)
+    if let Some(announce) = announce {
+        announce.completed();
+        announce.stop().await;
+    }
+    Ok(())
 }
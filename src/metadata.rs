@@ -0,0 +1,221 @@
+//! Fetching the info dictionary of a magnet link from peers (BEP 9).
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+
+use crate::extension::{ExtendedHandshake, ExtensionHandler};
+use crate::peer::{Peer, PeerMessage};
+use crate::BencodeValue;
+
+/// Metadata is exchanged in pieces of this size
+const METADATA_PIECE_SIZE: usize = 16384;
+/// Refuse to buffer more than this from a peer's `metadata_size`
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+#[derive(Default)]
+struct FetchState {
+    size: Option<usize>,
+    pieces: HashMap<usize, Vec<u8>>,
+    rejected: Option<usize>,
+}
+
+/// `ut_metadata` handler collecting the pieces of the info dictionary.
+/// Requests from the remote are rejected, we don't serve metadata.
+struct MetadataFetcher {
+    state: Arc<Mutex<FetchState>>,
+}
+
+impl ExtensionHandler for MetadataFetcher {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn on_handshake(&mut self, remote: &ExtendedHandshake) {
+        self.state.lock().unwrap().size = remote
+            .metadata_size
+            .filter(|&size| size > 0 && size <= MAX_METADATA_SIZE);
+    }
+
+    fn on_message(&mut self, payload: &[u8], replies: &mut Vec<Vec<u8>>) -> Result<()> {
+        let (header, data) = crate::decode_bencoded_value(payload)?;
+        let piece = header
+            .get("piece")
+            .and_then(|p| p.as_int())
+            .and_then(|p| usize::try_from(p).ok())
+            .context("ut_metadata message without piece")?;
+        let mut state = self.state.lock().unwrap();
+        match header.get("msg_type").and_then(|t| t.as_int()) {
+            Some(MSG_REQUEST) => replies.push(message(MSG_REJECT, piece)),
+            // Only pieces of the announced size are kept, so a peer can't
+            // make us buffer more than the metadata
+            Some(MSG_DATA) => {
+                let size = state.size.unwrap_or(0);
+                let expected = size
+                    .saturating_sub(piece.saturating_mul(METADATA_PIECE_SIZE))
+                    .min(METADATA_PIECE_SIZE);
+                if expected > 0 && data.len() == expected {
+                    state.pieces.insert(piece, data.to_vec());
+                }
+            }
+            Some(MSG_REJECT) => state.rejected = Some(piece),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn message(msg_type: i64, piece: usize) -> Vec<u8> {
+    let mut header = BTreeMap::new();
+    header.insert(b"msg_type".to_vec(), msg_type.into());
+    header.insert(b"piece".to_vec(), (piece as i64).into());
+    BencodeValue::Dict(header).encode()
+}
+
+/// Downloads the info dictionary from a peer and checks it against `info_hash`.
+pub async fn fetch_metadata(peer: SocketAddr, info_hash: [u8; 20]) -> Result<Vec<u8>> {
+    let mut peer = Peer::connect_peer(peer, info_hash).await?;
+    if !peer.supports_extensions() {
+        bail!("peer does not support the extension protocol");
+    }
+    let state = Arc::new(Mutex::new(FetchState::default()));
+    peer.extensions.register(Box::new(MetadataFetcher {
+        state: state.clone(),
+    }));
+    peer.send_extended_handshake(ExtendedHandshake::default())
+        .await?;
+
+    // Wait for the remote's extended handshake, skipping bitfield and friends
+    while peer.extensions.remote().is_none() {
+        if let PeerMessage::Extended(id, payload) = peer.read_raw_message().await? {
+            peer.handle_extended(id, &payload).await?;
+        }
+    }
+    let size = state
+        .lock()
+        .unwrap()
+        .size
+        .context("peer sent no valid metadata_size")?;
+
+    let count = size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..count {
+        peer.send_extended("ut_metadata", &message(MSG_REQUEST, piece))
+            .await?;
+    }
+    loop {
+        {
+            let state = state.lock().unwrap();
+            if let Some(piece) = state.rejected {
+                bail!("peer rejected metadata piece {}", piece);
+            }
+            if (0..count).all(|piece| state.pieces.contains_key(&piece)) {
+                break;
+            }
+        }
+        if let PeerMessage::Extended(id, payload) = peer.read_raw_message().await? {
+            peer.handle_extended(id, &payload).await?;
+        }
+    }
+
+    let mut state = state.lock().unwrap();
+    let mut metadata = Vec::with_capacity(size);
+    for piece in 0..count {
+        let data = state.pieces.remove(&piece).expect("all pieces received");
+        let expected = METADATA_PIECE_SIZE.min(size - metadata.len());
+        if data.len() != expected {
+            bail!(
+                "metadata piece {} has {} bytes, expected {}",
+                piece,
+                data.len(),
+                expected
+            );
+        }
+        metadata.extend(data);
+    }
+
+    let hash: [u8; 20] = Sha1::digest(&metadata).into();
+    if hash != info_hash {
+        bail!("metadata does not match the info hash");
+    }
+    Ok(metadata)
+}
+
+#[test]
+fn ignores_unexpected_metadata_pieces() {
+    let state = Arc::new(Mutex::new(FetchState {
+        size: Some(METADATA_PIECE_SIZE + 10),
+        ..Default::default()
+    }));
+    let mut fetcher = MetadataFetcher {
+        state: state.clone(),
+    };
+    let data = |piece: usize, len: usize| {
+        let mut payload = format!("d8:msg_typei1e5:piecei{}ee", piece).into_bytes();
+        payload.extend(vec![0; len]);
+        payload
+    };
+    for (piece, len) in [(2, 10), (1, 11), (0, 2 * METADATA_PIECE_SIZE), (1, 10)] {
+        fetcher
+            .on_message(&data(piece, len), &mut Vec::new())
+            .unwrap();
+    }
+    let state = state.lock().unwrap();
+    assert_eq!(vec![&1], state.pieces.keys().collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn fetches_metadata_from_peer() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let torrent = crate::torrent::Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+    let info = torrent.info_bytes().to_vec();
+    let info_hash = torrent.info_hash();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let size = info.len();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(0x10, handshake[25] & 0x10);
+        handshake[48..].copy_from_slice(b"-XX0000-000000000000");
+        stream.write_all(&handshake).await.unwrap();
+
+        let send = |id: u8, body: Vec<u8>| {
+            let mut message = ((body.len() + 2) as u32).to_be_bytes().to_vec();
+            message.extend([20, id]);
+            message.extend(body);
+            message
+        };
+        let ours = format!("d1:md11:ut_metadatai3ee13:metadata_sizei{}ee", size);
+        stream.write_all(&send(0, ours.into_bytes())).await.unwrap();
+
+        loop {
+            let mut len = [0; 4];
+            if stream.read_exact(&mut len).await.is_err() {
+                return;
+            }
+            let mut message = vec![0; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut message).await.unwrap();
+            assert_eq!(20, message[0]);
+            if message[1] == 3 {
+                assert_eq!(b"d8:msg_typei0e5:piecei0ee", &message[2..]);
+                let mut body =
+                    format!("d8:msg_typei1e5:piecei0e10:total_sizei{}ee", size).into_bytes();
+                body.extend(&info);
+                stream.write_all(&send(1, body)).await.unwrap();
+            }
+        }
+    });
+
+    let metadata = fetch_metadata(addr, info_hash).await.unwrap();
+    assert_eq!(torrent.info_bytes(), &metadata[..]);
+}
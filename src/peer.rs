@@ -1,216 +1,766 @@
-use std::net::SocketAddrV4;
-
-use anyhow::{Context, Result};
-use bytes::{BufMut, BytesMut};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
 use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Instant};
+use tokio_util::codec::Framed;
 
-pub fn as_bytes_mut<T: Sized>(data: &mut T) -> &mut [u8] {
-    let ptr = data as *mut T as *mut u8;
-    let len = std::mem::size_of::<T>();
-    unsafe { std::slice::from_raw_parts_mut(ptr, len) }
-}
+use crate::bitfield::{Bitfield, SharedAvailability};
+use crate::extension::{self, ExtendedHandshake, Extensions};
+
+pub use codec::{HandshakeCodec, PeerCodec, MAX_FRAME_SIZE};
+
+mod codec;
 
-#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
-    pub length: u8,
     pub protocol: [u8; 19],
     pub reserved_bytes: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
 
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Reserved bytes with the extension protocol (BEP 10) bit set
+pub const EXTENSION_PROTOCOL: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0];
+
 impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Self {
-            length: 19,
-            protocol: *b"BitTorrent protocol",
+            protocol: *PROTOCOL,
             reserved_bytes: [0; 8],
             info_hash,
             peer_id,
         }
     }
+
+    /// Checks that the remote speaks the BitTorrent protocol and serves the
+    /// torrent we asked for.
+    pub fn validate(&self, info_hash: &[u8; 20]) -> Result<()> {
+        if &self.protocol != PROTOCOL {
+            bail!(
+                "not a BitTorrent peer: protocol {:?}",
+                String::from_utf8_lossy(&self.protocol)
+            );
+        }
+        if &self.info_hash != info_hash {
+            bail!(
+                "peer answered for torrent {} instead of {}",
+                hex::encode(self.info_hash),
+                hex::encode(info_hash)
+            );
+        }
+        Ok(())
+    }
 }
 
-#[repr(C)]
-pub struct Request {
-    index: [u8; 4],
-    begin: [u8; 4],
-    length: [u8; 4],
+/// What a peer supports, from the reserved bytes of its handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerCapabilities {
+    /// Extension protocol (BEP 10)
+    pub extension_protocol: bool,
+    /// Fast extension (BEP 6)
+    pub fast: bool,
+    /// Runs a DHT node and accepts `port` messages (BEP 5)
+    pub dht: bool,
 }
 
-impl Request {
-    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+impl PeerCapabilities {
+    pub fn from_reserved(reserved: [u8; 8]) -> Self {
         Self {
-            index: index.to_be_bytes(),
-            begin: begin.to_be_bytes(),
-            length: length.to_be_bytes(),
+            extension_protocol: reserved[5] & 0x10 != 0,
+            fast: reserved[7] & 0x04 != 0,
+            dht: reserved[7] & 0x01 != 0,
         }
     }
 
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
+    pub fn to_reserved(self) -> [u8; 8] {
+        let mut reserved = [0; 8];
+        if self.extension_protocol {
+            reserved[5] |= 0x10;
+        }
+        if self.fast {
+            reserved[7] |= 0x04;
+        }
+        if self.dht {
+            reserved[7] |= 0x01;
+        }
+        reserved
     }
-    pub fn length(&self) -> u32 {
-        u32::from_be_bytes(self.length)
+}
+
+impl fmt::Display for PeerCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = [
+            (self.extension_protocol, "extension protocol"),
+            (self.fast, "fast extension"),
+            (self.dht, "dht"),
+        ]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect();
+        if names.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&names.join(", "))
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct Piece {
-    index: [u8; 4],
-    begin: [u8; 4],
-    block: Vec<u8>,
+/// A block of a piece: the payload of `request`, `cancel` and `reject` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockInfo {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
 }
 
-impl Piece {
-    pub fn from_u8(bytes: &[u8]) -> Result<Self> {
-        Ok(Self {
-            index: bytes[..4].try_into()?,
-            begin: bytes[4..8].try_into()?,
-            block: bytes[8..].to_vec(),
-        })
-    }
+/// A message of the peer wire protocol, following the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bitfield),
+    Request(BlockInfo),
+    /// A block of data. Only the index and begin of the info go on the wire,
+    /// the length is the one of the data.
+    Piece(BlockInfo, Bytes),
+    Cancel(BlockInfo),
+    /// The port the remote's DHT node listens on (BEP 5)
+    Port(u16),
+    /// Fast extension (BEP 6): a piece the remote thinks we should download
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    /// Fast extension: the remote won't serve a block we requested
+    Reject(BlockInfo),
+    /// Fast extension: a piece we may request even while choked
+    AllowedFast(u32),
+    /// Extension protocol (BEP 10): the extension id and its payload
+    Extended(u8, Bytes),
+    /// A message we don't know, skipped by [`Peer::read_message`]
+    Unknown(u8, Bytes),
+}
 
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
-    }
-    pub fn block(&self) -> &[u8] {
-        &self.block
-    }
+/// Connection settings. Timeouts bound how long we wait on a peer, so a
+/// silent or stalled one can't hang us forever.
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    /// Extensions we advertise in the handshake
+    pub reserved: [u8; 8],
+    pub connect_timeout: Duration,
+    /// Longest wait for the remote's handshake
+    pub handshake_timeout: Duration,
+    /// Longest wait for the next message, keep-alives aside. Off by default:
+    /// a peer may rightly keep us choked for long, [`Self::idle_timeout`]
+    /// tells whether it is still alive.
+    pub read_timeout: Option<Duration>,
+    /// Longest a single write may take
+    pub write_timeout: Duration,
+    /// The connection is dropped when nothing at all arrives for this long
+    pub idle_timeout: Duration,
+    /// A keep-alive is sent when we have written nothing for this long
+    pub keep_alive_interval: Duration,
+    pub max_frame_size: usize,
 }
 
+/// What the remote told us about itself, kept up to date as its messages are read.
 #[derive(Debug, Clone)]
-pub struct Message {
-    pub tag: MessageTag,
-    pub payload: Vec<u8>,
-}
-
-impl Message {
-    fn to_bytes(&self) -> BytesMut {
-        let mut buffer = BytesMut::new();
-
-        // length is the payload + the message tag 1
-        let len_slice = u32::to_be_bytes(self.payload.len() as u32 + 1);
-
-        buffer.reserve(4 + self.payload.len() + 1);
-
-        buffer.extend_from_slice(&len_slice);
-        buffer.put_u8(self.tag as u8);
-        buffer.extend_from_slice(&self.payload);
-        buffer
-    }
-}
-
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum MessageTag {
-    Choke = 0,
-    Unchoke = 1,
-    Interested = 2,
-    NotInterested = 3,
-    Have = 4,
-    Bitfield = 5,
-    Request = 6,
-    Piece = 7,
-    Cancel = 8,
-}
-
-impl MessageTag {
-    fn from_u8(tag: u8) -> Result<Self> {
-        let message_tag = match tag {
-            0 => MessageTag::Choke,
-            1 => MessageTag::Unchoke,
-            2 => MessageTag::Interested,
-            3 => MessageTag::NotInterested,
-            4 => MessageTag::Have,
-            5 => MessageTag::Bitfield,
-            6 => MessageTag::Request,
-            7 => MessageTag::Piece,
-            8 => MessageTag::Cancel,
-            tag => {
-                return Err(anyhow::anyhow!("Unknown tag: {}", tag));
+pub struct RemoteState {
+    /// Whether the remote is choking us, as it does until it says otherwise
+    pub choking: bool,
+    /// Pieces we may request even while choked (BEP 6)
+    pub allowed_fast: HashSet<u32>,
+    /// Pieces the remote suggested we download, oldest first (BEP 6)
+    pub suggested: VecDeque<u32>,
+    /// Pieces the remote has, empty until [`Peer::track_pieces`]
+    pub pieces: Bitfield,
+}
+
+impl Default for RemoteState {
+    fn default() -> Self {
+        Self {
+            choking: true,
+            allowed_fast: HashSet::new(),
+            suggested: VecDeque::new(),
+            pieces: Bitfield::default(),
+        }
+    }
+}
+
+/// Suggestions beyond this many push out the oldest ones
+const MAX_SUGGESTED: usize = 32;
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            reserved: PeerCapabilities {
+                extension_protocol: true,
+                fast: true,
+                dht: false,
             }
-        };
-        Ok(message_tag)
+            .to_reserved(),
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(60),
+            read_timeout: None,
+            write_timeout: Duration::from_secs(30),
+            // Peers send keep-alives every two minutes
+            idle_timeout: Duration::from_secs(3 * 60),
+            keep_alive_interval: Duration::from_secs(90),
+            max_frame_size: codec::MAX_FRAME_SIZE,
+        }
+    }
+}
+
+type Connection = Framed<TcpStream, PeerCodec>;
+
+struct Writer {
+    sink: SplitSink<Connection, PeerMessage>,
+    last_write: Instant,
+}
+
+impl Writer {
+    async fn send(&mut self, message: PeerMessage, write_timeout: Duration) -> Result<()> {
+        timeout(write_timeout, self.sink.send(message))
+            .await
+            .context("timed out writing to peer")??;
+        self.last_write = Instant::now();
+        Ok(())
     }
 }
 
 pub struct Peer {
-    stream: TcpStream,
+    stream: SplitStream<Connection>,
+    writer: Arc<Mutex<Writer>>,
+    keep_alive: JoinHandle<()>,
+    config: PeerConfig,
+    pub addr: SocketAddr,
     pub peer_id: [u8; 20],
+    /// The reserved bytes the remote sent in its handshake
+    pub reserved: [u8; 8],
+    /// Extensions enabled on this connection, see [`Self::send_extended_handshake`]
+    pub extensions: Extensions,
+    pub remote: RemoteState,
+    /// The swarm availability this peer's pieces count towards
+    availability: Option<SharedAvailability>,
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.keep_alive.abort();
+        if let Some(availability) = &self.availability {
+            availability.lock().unwrap().remove(&self.remote.pieces);
+        }
+    }
 }
 
 impl Peer {
     /// Creates a new Peer, by creating a Tcp stream, then attempting a Handshake
     /// with the given peer address
     /// Returns an error if the handshake fails.
-    pub async fn connect_peer(peer: SocketAddrV4, info_hash: [u8; 20]) -> Result<Self> {
-        let mut connection = TcpStream::connect(peer)
+    pub async fn connect_peer(peer: SocketAddr, info_hash: [u8; 20]) -> Result<Self> {
+        Self::connect_peer_with(peer, info_hash, PeerConfig::default()).await
+    }
+
+    /// Like [`Self::connect_peer`], with the given settings.
+    pub async fn connect_peer_with(
+        peer: SocketAddr,
+        info_hash: [u8; 20],
+        config: PeerConfig,
+    ) -> Result<Self> {
+        let connection = timeout(config.connect_timeout, TcpStream::connect(peer))
             .await
+            .context("timed out connecting to peer")?
             .context("connecting to peer")?;
+        let mut framed = Framed::new(connection, HandshakeCodec);
 
         let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-
-        // Drops unsafe slice pointer after reading it
-        {
-            // Generates a mutable slice pointer to handshake
-            let bytes = as_bytes_mut(&mut handshake);
-
-            connection
-                .write_all(bytes)
-                .await
-                .context("sending request")?;
-
-            // Reads to the same bytes slice pointing to the handshake struct
-            connection
-                .read_exact(bytes)
-                .await
-                .context("recieving handshake")?;
-        }
+        handshake.reserved_bytes = config.reserved;
+        timeout(config.write_timeout, framed.send(handshake))
+            .await
+            .context("timed out sending handshake")?
+            .context("sending handshake")?;
+        let handshake = timeout(config.handshake_timeout, framed.next())
+            .await
+            .context("timed out waiting for the handshake")?
+            .context("peer closed the connection during the handshake")?
+            .context("recieving handshake")?;
+        handshake.validate(&info_hash)?;
+
+        let (sink, stream) = framed
+            .map_codec(|_| PeerCodec::new(config.max_frame_size))
+            .split();
+        let writer = Arc::new(Mutex::new(Writer {
+            sink,
+            last_write: Instant::now(),
+        }));
+        let keep_alive = tokio::spawn(keep_alive_loop(
+            Arc::downgrade(&writer),
+            config.keep_alive_interval,
+            config.write_timeout,
+        ));
         Ok(Self {
-            stream: connection,
+            stream,
+            writer,
+            keep_alive,
+            config,
+            addr: peer,
             peer_id: handshake.peer_id,
+            reserved: handshake.reserved_bytes,
+            extensions: Extensions::new(),
+            remote: RemoteState::default(),
+            availability: None,
         })
     }
 
-    pub async fn send_message(&mut self, message: Message) -> Result<()> {
-        eprintln!("Sending message: {:?}", message);
-        let bytes = message.to_bytes();
-        self.stream.write_all(&bytes).await?;
+    /// Starts tracking which pieces the remote has, from the messages read
+    /// from now on, and counting them in the swarm `availability`.
+    pub fn track_pieces(&mut self, availability: SharedAvailability) {
+        let num_pieces = availability.lock().unwrap().num_pieces();
+        self.remote.pieces = Bitfield::new(num_pieces);
+        self.availability = Some(availability);
+    }
+
+    /// Whether the remote told us it has piece `index`.
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.remote.pieces.get(index)
+    }
+
+    /// What the remote advertised in its handshake.
+    pub fn capabilities(&self) -> PeerCapabilities {
+        PeerCapabilities::from_reserved(self.reserved)
+    }
 
-        eprintln!("Message sent!\n");
+    /// Whether both sides enabled the fast extension (BEP 6).
+    pub fn supports_fast(&self) -> bool {
+        self.capabilities().fast && PeerCapabilities::from_reserved(self.config.reserved).fast
+    }
+
+    /// Whether the remote set the extension protocol bit in its handshake.
+    pub fn supports_extensions(&self) -> bool {
+        self.capabilities().extension_protocol
+    }
+
+    /// Sends our extended handshake: `base` plus the registered extensions,
+    /// our client version and the address we see the remote at.
+    pub async fn send_extended_handshake(&mut self, mut base: ExtendedHandshake) -> Result<()> {
+        base.v
+            .get_or_insert_with(|| format!("torrust {}", env!("CARGO_PKG_VERSION")));
+        base.yourip = Some(self.addr.ip());
+        let handshake = self.extensions.handshake(base).to_bytes();
+        self.send_message(PeerMessage::Extended(
+            extension::HANDSHAKE_ID,
+            handshake.into(),
+        ))
+        .await
+    }
+
+    /// Sends a message of extension `name`, which the remote must have enabled.
+    pub async fn send_extended(&mut self, name: &str, body: &[u8]) -> Result<()> {
+        let id = self
+            .extensions
+            .remote_id(name)
+            .with_context(|| format!("peer does not support {}", name))?;
+        self.send_message(PeerMessage::Extended(id, Bytes::copy_from_slice(body)))
+            .await
+    }
 
+    /// Routes an extended message to its handler and sends back the replies.
+    pub async fn handle_extended(&mut self, id: u8, payload: &[u8]) -> Result<()> {
+        let message = [&[id], payload].concat();
+        for reply in self.extensions.dispatch(&message)? {
+            let (&id, body) = reply.split_first().expect("replies carry an id");
+            self.send_message(PeerMessage::Extended(id, Bytes::copy_from_slice(body)))
+                .await?;
+        }
         Ok(())
     }
 
-    pub async fn read_message(&mut self) -> Result<Message> {
-        let mut message_length: [u8; 4] = [0; 4];
+    pub async fn send_message(&mut self, message: PeerMessage) -> Result<()> {
+        self.writer
+            .lock()
+            .await
+            .send(message, self.config.write_timeout)
+            .await
+    }
 
-        self.stream.read_exact(&mut message_length).await?;
+    /// Reads the next message, handling extended messages on the way.
+    pub async fn read_message(&mut self) -> Result<PeerMessage> {
+        loop {
+            match self.read_raw_message().await? {
+                PeerMessage::Extended(id, payload) => self.handle_extended(id, &payload).await?,
+                message => return Ok(message),
+            }
+        }
+    }
 
-        let message_length = u32::from_be_bytes(message_length);
+    /// Reads the next message as is, extended messages included. Keep-alives
+    /// are skipped but keep the connection from timing out as idle, unknown
+    /// messages are skipped too.
+    pub async fn read_raw_message(&mut self) -> Result<PeerMessage> {
+        let deadline = self.config.read_timeout.map(|t| Instant::now() + t);
+        loop {
+            let wait = deadline.map_or(self.config.idle_timeout, |deadline| {
+                self.config
+                    .idle_timeout
+                    .min(deadline.saturating_duration_since(Instant::now()))
+            });
+            let message = match timeout(wait, self.stream.next()).await {
+                Ok(message) => message.context("peer closed the connection")??,
+                Err(_) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                    bail!("timed out waiting for a message")
+                }
+                Err(_) => bail!("peer idle for {:?}", self.config.idle_timeout),
+            };
+            match message {
+                PeerMessage::KeepAlive => continue,
+                PeerMessage::Unknown(id, _) => eprintln!("Skipping unknown message {}", id),
+                message => {
+                    self.update_remote(&message)?;
+                    return Ok(message);
+                }
+            }
+        }
+    }
 
-        let mut message_type: [u8; 1] = [0; 1];
-        self.stream.read_exact(&mut message_type).await?;
+    fn update_remote(&mut self, message: &PeerMessage) -> Result<()> {
+        let fast_only = matches!(
+            message,
+            PeerMessage::Suggest(_)
+                | PeerMessage::HaveAll
+                | PeerMessage::HaveNone
+                | PeerMessage::Reject(_)
+                | PeerMessage::AllowedFast(_)
+        );
+        if fast_only && !self.supports_fast() {
+            bail!(
+                "fast extension message without negotiating it: {:?}",
+                message
+            );
+        }
 
-        let tag = message_type[0];
-        let message_tag = MessageTag::from_u8(tag)?;
+        if let Some(availability) = &self.availability {
+            let num_pieces = self.remote.pieces.len();
+            let pieces = match message {
+                PeerMessage::Bitfield(bitfield) => Some(bitfield.clone().for_pieces(num_pieces)?),
+                PeerMessage::HaveAll => Some(Bitfield::full(num_pieces)),
+                PeerMessage::HaveNone => Some(Bitfield::new(num_pieces)),
+                _ => None,
+            };
+            let mut availability = availability.lock().unwrap();
+            if let Some(pieces) = pieces {
+                availability.remove(&self.remote.pieces);
+                availability.add(&pieces);
+                self.remote.pieces = pieces;
+            } else if let PeerMessage::Have(index) = *message {
+                let index = index as usize;
+                if index >= num_pieces {
+                    bail!("have for piece {} out of {}", index, num_pieces);
+                }
+                if !self.remote.pieces.get(index) {
+                    self.remote.pieces.set(index, true);
+                    availability.add_piece(index);
+                }
+            }
+        }
 
-        let mut payload: Vec<u8> = vec![0; message_length as usize - 1];
-        // Read a message of length message_length - 1 (message_tag is already read)
-        self.stream.read_exact(&mut payload).await?;
+        let remote = &mut self.remote;
+        match *message {
+            PeerMessage::Choke => remote.choking = true,
+            PeerMessage::Unchoke => remote.choking = false,
+            PeerMessage::AllowedFast(index) => {
+                remote.allowed_fast.insert(index);
+            }
+            PeerMessage::Suggest(index) => {
+                remote.suggested.retain(|&i| i != index);
+                if remote.suggested.len() == MAX_SUGGESTED {
+                    remote.suggested.pop_front();
+                }
+                remote.suggested.push_back(index);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
 
-        let message = Message {
-            tag: message_tag,
-            payload,
+/// Sends a keep-alive whenever nothing was written for `interval`, until the
+/// peer is dropped or a write fails.
+async fn keep_alive_loop(writer: Weak<Mutex<Writer>>, interval: Duration, write_timeout: Duration) {
+    let mut ticks = tokio::time::interval(interval / 2);
+    loop {
+        ticks.tick().await;
+        let Some(writer) = writer.upgrade() else {
+            return;
         };
-        Ok(message)
+        let mut writer = writer.lock().await;
+        if writer.last_write.elapsed() >= interval
+            && writer
+                .send(PeerMessage::KeepAlive, write_timeout)
+                .await
+                .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// A fake remote that answers the handshake, then hands over the connection.
+#[cfg(test)]
+async fn fake_peer() -> (SocketAddr, tokio::task::JoinHandle<TcpStream>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let remote = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; codec::HANDSHAKE_LEN];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream.write_all(&handshake).await.unwrap();
+        stream
+    });
+    (addr, remote)
+}
+
+#[cfg(test)]
+fn short_timeouts() -> PeerConfig {
+    PeerConfig {
+        read_timeout: Some(Duration::from_millis(400)),
+        idle_timeout: Duration::from_millis(200),
+        keep_alive_interval: Duration::from_millis(50),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn keep_alives_are_skipped_and_sent() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (addr, remote) = fake_peer().await;
+    let mut peer = Peer::connect_peer_with(addr, [0; 20], short_timeouts())
+        .await
+        .unwrap();
+    let mut remote = remote.await.unwrap();
+
+    remote.write_all(&[0, 0, 0, 0, 0, 0, 0, 0]).await.unwrap();
+    // Unknown messages are skipped too
+    remote.write_all(&[0, 0, 0, 3, 0x7f, 1, 2]).await.unwrap();
+    remote.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+    assert_eq!(PeerMessage::Unchoke, peer.read_message().await.unwrap());
+
+    let mut keep_alive = [0xff; 4];
+    remote.read_exact(&mut keep_alive).await.unwrap();
+    assert_eq!([0; 4], keep_alive);
+}
+
+#[tokio::test]
+async fn silent_and_misbehaving_peers_time_out() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, remote) = fake_peer().await;
+    let mut peer = Peer::connect_peer_with(addr, [0; 20], short_timeouts())
+        .await
+        .unwrap();
+    let _remote = remote.await.unwrap();
+    let error = peer.read_message().await.unwrap_err();
+    assert!(error.to_string().contains("idle"), "{}", error);
+
+    // Keep-alives alone don't make a message arrive
+    let (addr, remote) = fake_peer().await;
+    let mut peer = Peer::connect_peer_with(addr, [0; 20], short_timeouts())
+        .await
+        .unwrap();
+    let mut remote = remote.await.unwrap();
+    let keep_alives = tokio::spawn(async move {
+        while remote.write_all(&[0; 4]).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    });
+    let error = peer.read_message().await.unwrap_err();
+    assert!(error.to_string().contains("waiting"), "{}", error);
+    keep_alives.abort();
+
+    // Unless the read timeout is off, as it is by default
+    let (addr, remote) = fake_peer().await;
+    let config = PeerConfig {
+        read_timeout: None,
+        ..short_timeouts()
+    };
+    let mut peer = Peer::connect_peer_with(addr, [0; 20], config)
+        .await
+        .unwrap();
+    let mut remote = remote.await.unwrap();
+    let keep_alives = tokio::spawn(async move {
+        for _ in 0..12 {
+            remote.write_all(&[0; 4]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        remote.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        remote
+    });
+    assert_eq!(PeerMessage::Unchoke, peer.read_message().await.unwrap());
+    drop(keep_alives.await.unwrap());
+
+    let (addr, remote) = fake_peer().await;
+    let mut peer = Peer::connect_peer_with(addr, [0; 20], short_timeouts())
+        .await
+        .unwrap();
+    let mut remote = remote.await.unwrap();
+    remote
+        .write_all(&[0xff, 0xff, 0xff, 0xff, 7])
+        .await
+        .unwrap();
+    assert!(peer.read_message().await.is_err());
+}
+
+#[test]
+fn capabilities_from_reserved_bits() {
+    let capabilities = PeerCapabilities::from_reserved([0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+    assert_eq!(
+        PeerCapabilities {
+            extension_protocol: true,
+            fast: true,
+            dht: true
+        },
+        capabilities
+    );
+    assert_eq!([0, 0, 0, 0, 0, 0x10, 0, 0x05], capabilities.to_reserved());
+    assert_eq!(
+        "extension protocol, fast extension, dht",
+        capabilities.to_string()
+    );
+    assert_eq!(
+        "none",
+        PeerCapabilities::from_reserved([0xff, 0xff, 0xff, 0xff, 0xff, 0xef, 0xff, 0xfa])
+            .to_string()
+    );
+}
+
+#[tokio::test]
+async fn rejects_wrong_torrent_and_protocol() {
+    // Echo our handshake, tampering with it on the way back
+    for (offset, expected) in [(1, "not a BitTorrent peer"), (28, "instead of")] {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; codec::HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+            handshake[offset] ^= 0xff;
+            stream.write_all(&handshake).await.unwrap();
+            stream
+        });
+        let error = Peer::connect_peer(addr, [1; 20]).await.err().unwrap();
+        assert!(error.to_string().contains(expected), "{}", error);
+        remote.await.unwrap();
     }
 }
+
+#[tokio::test]
+async fn tracks_fast_extension_state() {
+    use tokio::io::AsyncWriteExt;
+
+    let (addr, remote) = fake_peer().await;
+    let mut peer = Peer::connect_peer(addr, [0; 20]).await.unwrap();
+    let mut remote = remote.await.unwrap();
+    assert!(peer.supports_fast());
+    assert!(peer.remote.choking);
+
+    // Have All, Allowed Fast 3, Suggest 5, Reject 3/0/16384
+    remote.write_all(b"\x00\x00\x00\x01\x0e").await.unwrap();
+    remote
+        .write_all(b"\x00\x00\x00\x05\x11\x00\x00\x00\x03")
+        .await
+        .unwrap();
+    remote
+        .write_all(b"\x00\x00\x00\x05\x0d\x00\x00\x00\x05")
+        .await
+        .unwrap();
+    remote
+        .write_all(b"\x00\x00\x00\x0d\x10\x00\x00\x00\x03\x00\x00\x00\x00\x00\x00\x40\x00")
+        .await
+        .unwrap();
+    assert_eq!(PeerMessage::HaveAll, peer.read_message().await.unwrap());
+    assert_eq!(
+        PeerMessage::AllowedFast(3),
+        peer.read_message().await.unwrap()
+    );
+    assert_eq!(PeerMessage::Suggest(5), peer.read_message().await.unwrap());
+    assert_eq!(
+        PeerMessage::Reject(BlockInfo {
+            index: 3,
+            begin: 0,
+            length: 16384
+        }),
+        peer.read_message().await.unwrap()
+    );
+    assert!(peer.remote.allowed_fast.contains(&3));
+    assert_eq!(vec![5], Vec::from(peer.remote.suggested.clone()));
+
+    // Without negotiating it, fast extension messages are a protocol error
+    let (addr, remote) = fake_peer().await;
+    let config = PeerConfig {
+        reserved: EXTENSION_PROTOCOL,
+        ..Default::default()
+    };
+    let mut peer = Peer::connect_peer_with(addr, [0; 20], config)
+        .await
+        .unwrap();
+    let mut remote = remote.await.unwrap();
+    remote.write_all(b"\x00\x00\x00\x01\x0e").await.unwrap();
+    assert!(peer.read_message().await.is_err());
+}
+
+#[tokio::test]
+async fn tracks_pieces_and_availability() {
+    use tokio::io::AsyncWriteExt;
+
+    let availability = crate::bitfield::Availability::shared(10);
+    let (addr, remote) = fake_peer().await;
+    let mut peer = Peer::connect_peer(addr, [0; 20]).await.unwrap();
+    let mut remote = remote.await.unwrap();
+    peer.track_pieces(availability.clone());
+
+    // Bitfield with pieces 0 and 9, then Have 4
+    remote
+        .write_all(b"\x00\x00\x00\x03\x05\x80\x40\x00\x00\x00\x05\x04\x00\x00\x00\x04")
+        .await
+        .unwrap();
+    peer.read_message().await.unwrap();
+    peer.read_message().await.unwrap();
+    assert_eq!(
+        vec![0, 4, 9],
+        peer.remote.pieces.iter_ones().collect::<Vec<_>>()
+    );
+    assert!(peer.has_piece(4) && !peer.has_piece(5));
+    assert_eq!(1, availability.lock().unwrap().count(4));
+
+    drop(peer);
+    assert_eq!(0, availability.lock().unwrap().count(4));
+
+    // A spare bit set is a protocol error
+    let (addr, remote) = fake_peer().await;
+    let mut peer = Peer::connect_peer(addr, [0; 20]).await.unwrap();
+    let mut remote = remote.await.unwrap();
+    peer.track_pieces(availability.clone());
+    remote
+        .write_all(b"\x00\x00\x00\x03\x05\x00\x20")
+        .await
+        .unwrap();
+    assert!(peer.read_message().await.is_err());
+}
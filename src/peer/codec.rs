@@ -0,0 +1,373 @@
+//! Wire format of the peer protocol: the fixed size handshake, then length
+//! prefixed messages.
+
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{BlockInfo, Handshake, PeerMessage, PROTOCOL};
+use crate::bitfield::Bitfield;
+
+pub const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+
+/// Frames the handshake. Once it is exchanged, switch to [`PeerCodec`] with
+/// `Framed::map_codec`, which keeps whatever was already buffered.
+#[derive(Debug, Default)]
+pub struct HandshakeCodec;
+
+impl Encoder<Handshake> for HandshakeCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, handshake: Handshake, dst: &mut BytesMut) -> io::Result<()> {
+        dst.reserve(HANDSHAKE_LEN);
+        dst.put_u8(handshake.protocol.len() as u8);
+        dst.extend_from_slice(&handshake.protocol);
+        dst.extend_from_slice(&handshake.reserved_bytes);
+        dst.extend_from_slice(&handshake.info_hash);
+        dst.extend_from_slice(&handshake.peer_id);
+        Ok(())
+    }
+}
+
+impl Decoder for HandshakeCodec {
+    type Item = Handshake;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Handshake>> {
+        if src.len() < HANDSHAKE_LEN {
+            src.reserve(HANDSHAKE_LEN - src.len());
+            return Ok(None);
+        }
+        let length = src.get_u8();
+        if length as usize != PROTOCOL.len() {
+            return Err(invalid_data(format!(
+                "protocol string of length {}",
+                length
+            )));
+        }
+        let mut handshake = Handshake::new([0; 20], [0; 20]);
+        src.copy_to_slice(&mut handshake.protocol);
+        src.copy_to_slice(&mut handshake.reserved_bytes);
+        src.copy_to_slice(&mut handshake.info_hash);
+        src.copy_to_slice(&mut handshake.peer_id);
+        Ok(Some(handshake))
+    }
+}
+
+/// Largest frame accepted by default: a block plus its header, or the
+/// bitfield of a torrent with two million pieces
+pub const MAX_FRAME_SIZE: usize = 256 * 1024;
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+const SUGGEST: u8 = 0x0d;
+const HAVE_ALL: u8 = 0x0e;
+const HAVE_NONE: u8 = 0x0f;
+const REJECT: u8 = 0x10;
+const ALLOWED_FAST: u8 = 0x11;
+const EXTENDED: u8 = 20;
+
+/// Frames peer wire messages: a 4 byte big endian length, the id and the payload.
+#[derive(Debug)]
+pub struct PeerCodec {
+    max_frame_size: usize,
+}
+
+impl PeerCodec {
+    /// A codec rejecting frames longer than `max_frame_size`, length prefix excluded.
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+}
+
+impl Default for PeerCodec {
+    fn default() -> Self {
+        Self::new(MAX_FRAME_SIZE)
+    }
+}
+
+impl Encoder<PeerMessage> for PeerCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: PeerMessage, dst: &mut BytesMut) -> io::Result<()> {
+        let (id, payload): (u8, Bytes) = match message {
+            PeerMessage::KeepAlive => {
+                dst.put_u32(0);
+                return Ok(());
+            }
+            PeerMessage::Choke => (CHOKE, Bytes::new()),
+            PeerMessage::Unchoke => (UNCHOKE, Bytes::new()),
+            PeerMessage::Interested => (INTERESTED, Bytes::new()),
+            PeerMessage::NotInterested => (NOT_INTERESTED, Bytes::new()),
+            PeerMessage::Have(index) => (HAVE, index.to_be_bytes().to_vec().into()),
+            PeerMessage::Bitfield(bitfield) => (BITFIELD, bitfield.as_bytes().to_vec().into()),
+            PeerMessage::Request(block) => (REQUEST, block_info(&block)),
+            PeerMessage::Piece(block, data) => {
+                let mut payload = BytesMut::with_capacity(8 + data.len());
+                payload.put_u32(block.index);
+                payload.put_u32(block.begin);
+                payload.extend_from_slice(&data);
+                (PIECE, payload.freeze())
+            }
+            PeerMessage::Cancel(block) => (CANCEL, block_info(&block)),
+            PeerMessage::Port(port) => (PORT, port.to_be_bytes().to_vec().into()),
+            PeerMessage::Suggest(index) => (SUGGEST, index.to_be_bytes().to_vec().into()),
+            PeerMessage::HaveAll => (HAVE_ALL, Bytes::new()),
+            PeerMessage::HaveNone => (HAVE_NONE, Bytes::new()),
+            PeerMessage::Reject(block) => (REJECT, block_info(&block)),
+            PeerMessage::AllowedFast(index) => (ALLOWED_FAST, index.to_be_bytes().to_vec().into()),
+            PeerMessage::Extended(extension, payload) => {
+                let mut body = BytesMut::with_capacity(1 + payload.len());
+                body.put_u8(extension);
+                body.extend_from_slice(&payload);
+                (EXTENDED, body.freeze())
+            }
+            PeerMessage::Unknown(id, payload) => (id, payload),
+        };
+
+        // length is the payload + the message id 1
+        let length = payload.len() + 1;
+        if length > self.max_frame_size {
+            return Err(invalid_data(format!("{} byte frame is too long", length)));
+        }
+        dst.reserve(4 + length);
+        dst.put_u32(length as u32);
+        dst.put_u8(id);
+        dst.extend_from_slice(&payload);
+        Ok(())
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = PeerMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<PeerMessage>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(src[..4].try_into().unwrap()) as usize;
+        if length == 0 {
+            src.advance(4);
+            return Ok(Some(PeerMessage::KeepAlive));
+        }
+        // Checked before buffering anything, so a bogus length can't make us allocate it
+        if length > self.max_frame_size {
+            return Err(invalid_data(format!("{} byte frame is too long", length)));
+        }
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let id = src.get_u8();
+        let payload = src.split_to(length - 1).freeze();
+        parse_message(id, payload).map(Some)
+    }
+}
+
+fn parse_message(id: u8, mut payload: Bytes) -> io::Result<PeerMessage> {
+    let len = payload.len();
+    let expect = |valid: bool| {
+        if valid {
+            Ok(())
+        } else {
+            Err(invalid_data(format!(
+                "message {} with a payload of {} bytes",
+                id, len
+            )))
+        }
+    };
+    let message = match id {
+        CHOKE | UNCHOKE | INTERESTED | NOT_INTERESTED | HAVE_ALL | HAVE_NONE => {
+            expect(len == 0)?;
+            match id {
+                CHOKE => PeerMessage::Choke,
+                UNCHOKE => PeerMessage::Unchoke,
+                INTERESTED => PeerMessage::Interested,
+                NOT_INTERESTED => PeerMessage::NotInterested,
+                HAVE_ALL => PeerMessage::HaveAll,
+                _ => PeerMessage::HaveNone,
+            }
+        }
+        HAVE | SUGGEST | ALLOWED_FAST => {
+            expect(len == 4)?;
+            let index = payload.get_u32();
+            match id {
+                HAVE => PeerMessage::Have(index),
+                SUGGEST => PeerMessage::Suggest(index),
+                _ => PeerMessage::AllowedFast(index),
+            }
+        }
+        BITFIELD => PeerMessage::Bitfield(Bitfield::from_bytes(payload.to_vec())),
+        REQUEST | CANCEL | REJECT => {
+            expect(len == 12)?;
+            let block = BlockInfo {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            };
+            match id {
+                REQUEST => PeerMessage::Request(block),
+                CANCEL => PeerMessage::Cancel(block),
+                _ => PeerMessage::Reject(block),
+            }
+        }
+        PIECE => {
+            expect(len >= 8)?;
+            let index = payload.get_u32();
+            let begin = payload.get_u32();
+            let block = BlockInfo {
+                index,
+                begin,
+                length: payload.len() as u32,
+            };
+            PeerMessage::Piece(block, payload)
+        }
+        PORT => {
+            expect(len == 2)?;
+            PeerMessage::Port(payload.get_u16())
+        }
+        EXTENDED => {
+            expect(len >= 1)?;
+            let extension = payload.get_u8();
+            PeerMessage::Extended(extension, payload)
+        }
+        id => PeerMessage::Unknown(id, payload),
+    };
+    Ok(message)
+}
+
+fn block_info(block: &BlockInfo) -> Bytes {
+    let mut payload = BytesMut::with_capacity(12);
+    payload.put_u32(block.index);
+    payload.put_u32(block.begin);
+    payload.put_u32(block.length);
+    payload.freeze()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+fn round_trip<C, T>(codec: &mut C, item: T) -> T
+where
+    C: Encoder<T, Error = io::Error> + Decoder<Item = T, Error = io::Error>,
+{
+    let mut buffer = BytesMut::new();
+    codec.encode(item, &mut buffer).unwrap();
+    let encoded = buffer.clone();
+
+    // Nothing comes out until the whole frame is there
+    let mut partial = BytesMut::from(&encoded[..encoded.len() - 1]);
+    assert!(codec.decode(&mut partial).unwrap().is_none());
+
+    let decoded = codec.decode(&mut buffer).unwrap().unwrap();
+    assert!(buffer.is_empty());
+    decoded
+}
+
+#[test]
+fn handshake_round_trip() {
+    let mut handshake = Handshake::new([1; 20], *b"-TR0001-abcdefghijkl");
+    handshake.reserved_bytes = super::EXTENSION_PROTOCOL;
+    let decoded = round_trip(&mut HandshakeCodec, handshake.clone());
+    assert_eq!(handshake, decoded);
+
+    let mut buffer = BytesMut::new();
+    HandshakeCodec.encode(handshake, &mut buffer).unwrap();
+    assert_eq!(HANDSHAKE_LEN, buffer.len());
+    assert_eq!(b"\x13BitTorrent protocol", &buffer[..20]);
+    buffer[0] = 18;
+    assert!(HandshakeCodec.decode(&mut buffer).is_err());
+}
+
+#[test]
+fn message_round_trips() {
+    let block = BlockInfo {
+        index: 1,
+        begin: 16384,
+        length: 16384,
+    };
+    let messages = [
+        PeerMessage::KeepAlive,
+        PeerMessage::Choke,
+        PeerMessage::Unchoke,
+        PeerMessage::Interested,
+        PeerMessage::NotInterested,
+        PeerMessage::Have(7),
+        PeerMessage::Bitfield(Bitfield::from_bytes(vec![0b1010_0000, 0xff])),
+        PeerMessage::Request(block),
+        PeerMessage::Piece(
+            BlockInfo {
+                length: 10,
+                ..block
+            },
+            Bytes::from_static(b"block data"),
+        ),
+        PeerMessage::Cancel(block),
+        PeerMessage::Port(6881),
+        PeerMessage::Suggest(3),
+        PeerMessage::HaveAll,
+        PeerMessage::HaveNone,
+        PeerMessage::Reject(block),
+        PeerMessage::AllowedFast(9),
+        PeerMessage::Extended(0, Bytes::from_static(b"d1:md6:ut_pexi1eee")),
+        PeerMessage::Unknown(0x7f, Bytes::from_static(b"?")),
+    ];
+    let mut codec = PeerCodec::default();
+    for message in messages {
+        assert_eq!(message, round_trip(&mut codec, message.clone()));
+    }
+}
+
+#[test]
+fn decodes_a_stream_of_frames() {
+    let mut codec = PeerCodec::default();
+    let mut buffer =
+        BytesMut::from(&b"\x00\x00\x00\x00\x00\x00\x00\x01\x01\x00\x00\x00\x05\x04\x00\x00"[..]);
+    assert_eq!(
+        Some(PeerMessage::KeepAlive),
+        codec.decode(&mut buffer).unwrap()
+    );
+    assert_eq!(
+        Some(PeerMessage::Unchoke),
+        codec.decode(&mut buffer).unwrap()
+    );
+    assert!(codec.decode(&mut buffer).unwrap().is_none());
+    buffer.extend_from_slice(&[0, 3]);
+    assert_eq!(
+        Some(PeerMessage::Have(3)),
+        codec.decode(&mut buffer).unwrap()
+    );
+
+    let mut port = BytesMut::from(&b"\x00\x00\x00\x03\x09\x1a\xe1"[..]);
+    assert_eq!(
+        Some(PeerMessage::Port(6881)),
+        codec.decode(&mut port).unwrap()
+    );
+    let mut short_have = BytesMut::from(&b"\x00\x00\x00\x02\x04\x00"[..]);
+    assert!(codec.decode(&mut short_have).is_err());
+}
+
+#[test]
+fn rejects_oversized_frames() {
+    let mut codec = PeerCodec::new(16);
+    let mut huge = BytesMut::from(&b"\xff\xff\xff\xff\x07"[..]);
+    assert!(codec.decode(&mut huge).is_err());
+    assert!(huge.capacity() < 1024);
+
+    let long = PeerMessage::Bitfield(Bitfield::from_bytes(vec![0; 16]));
+    assert!(codec.encode(long, &mut BytesMut::new()).is_err());
+}
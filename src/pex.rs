@@ -0,0 +1,237 @@
+//! Peer exchange (BEP 11): peers tell each other about the peers they are
+//! connected to, as the `ut_pex` extension.
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use tokio::sync::mpsc;
+
+use crate::extension::ExtensionHandler;
+use crate::BencodeValue;
+
+/// Peers should not be sent PEX messages more often than this.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Most peers added or dropped in a single message
+const MAX_PEERS: usize = 50;
+
+/// `added.f` flag: the peer is a seed
+pub const FLAG_SEED: u8 = 0x02;
+/// `added.f` flag: the peer accepts incoming connections
+pub const FLAG_CONNECTABLE: u8 = 0x10;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    /// New peers and their flags
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (added4, added6): (Vec<_>, Vec<_>) = self.added.iter().partition(|(a, _)| a.is_ipv4());
+        let (dropped4, dropped6): (Vec<_>, Vec<_>) = self.dropped.iter().partition(|a| a.is_ipv4());
+
+        let mut dict = BTreeMap::new();
+        let mut put = |key: &str, value: Vec<u8>| {
+            dict.insert(key.as_bytes().to_vec(), BencodeValue::Bytes(value));
+        };
+        put(
+            "added",
+            added4.iter().flat_map(|(a, _)| compact(a)).collect(),
+        );
+        put("added.f", added4.iter().map(|(_, f)| *f).collect());
+        put(
+            "added6",
+            added6.iter().flat_map(|(a, _)| compact(a)).collect(),
+        );
+        put("added6.f", added6.iter().map(|(_, f)| *f).collect());
+        put("dropped", dropped4.iter().flat_map(compact).collect());
+        put("dropped6", dropped6.iter().flat_map(compact).collect());
+        BencodeValue::Dict(dict).encode()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (dict, _) = crate::decode_bencoded_value(bytes)?;
+        dict.as_dict().context("pex message is not a dictionary")?;
+        let bytes = |key| dict.get(key).and_then(|v| v.as_bytes()).unwrap_or_default();
+        let peers = |key, width| parse_compact(bytes(key), width);
+        let with_flags = |key, flags_key, width| {
+            let flags = bytes(flags_key);
+            peers(key, width)
+                .into_iter()
+                .enumerate()
+                .map(|(i, peer)| (peer, flags.get(i).copied().unwrap_or(0)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut added = with_flags("added", "added.f", 6);
+        added.extend(with_flags("added6", "added6.f", 18));
+        let mut dropped = peers("dropped", 6);
+        dropped.extend(peers("dropped6", 18));
+        Ok(Self { added, dropped })
+    }
+}
+
+fn compact(addr: &SocketAddr) -> Vec<u8> {
+    let mut compact = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    compact.extend(addr.port().to_be_bytes());
+    compact
+}
+
+fn parse_compact(bytes: &[u8], width: usize) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(width)
+        .filter_map(|c| {
+            let (ip, port) = c.split_at(width - 2);
+            let ip = match ip.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+                _ => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+            };
+            Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+        })
+        .collect()
+}
+
+/// Receiving side of `ut_pex`: forwards the peers a remote tells us about to
+/// the connection manager. Never register it for private torrents (BEP 27).
+pub struct PexHandler {
+    discovered: mpsc::UnboundedSender<SocketAddr>,
+}
+
+impl PexHandler {
+    pub fn new(discovered: mpsc::UnboundedSender<SocketAddr>) -> Self {
+        Self { discovered }
+    }
+}
+
+impl ExtensionHandler for PexHandler {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_message(&mut self, payload: &[u8], _replies: &mut Vec<Vec<u8>>) -> Result<()> {
+        let message = PexMessage::from_bytes(payload)?;
+        for (peer, _) in message.added.into_iter().take(MAX_PEERS) {
+            if !peer.ip().is_unspecified() && peer.port() != 0 {
+                let _ = self.discovered.send(peer);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sending side of `ut_pex` for one connection: tracks what the remote was
+/// already told and produces the next diff once the interval has passed.
+#[derive(Debug, Default)]
+pub struct PexSender {
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl PexSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The message to send now, given the peers we are connected to, if the
+    /// interval has passed and something changed. The recipient itself is never included.
+    pub fn message(
+        &mut self,
+        connected: &HashSet<SocketAddr>,
+        recipient: SocketAddr,
+    ) -> Option<Vec<u8>> {
+        if self.last_sent.is_some_and(|t| t.elapsed() < PEX_INTERVAL) {
+            return None;
+        }
+        let added: Vec<SocketAddr> = connected
+            .iter()
+            .filter(|&&p| p != recipient && !self.sent.contains(&p))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .iter()
+            .filter(|p| !connected.contains(p))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        self.sent.extend(&added);
+        for peer in &dropped {
+            self.sent.remove(peer);
+        }
+        self.last_sent = Some(Instant::now());
+        Some(
+            PexMessage {
+                // We only know that the peers we connected to accept connections
+                added: added.into_iter().map(|p| (p, FLAG_CONNECTABLE)).collect(),
+                dropped,
+            }
+            .to_bytes(),
+        )
+    }
+}
+
+#[test]
+fn message_round_trip() {
+    let message = PexMessage {
+        added: vec![
+            ("10.0.0.1:6881".parse().unwrap(), FLAG_SEED),
+            ("[2001:db8::1]:6882".parse().unwrap(), 0),
+        ],
+        dropped: vec!["10.0.0.2:1".parse().unwrap()],
+    };
+    assert_eq!(
+        message,
+        PexMessage::from_bytes(&message.to_bytes()).unwrap()
+    );
+}
+
+#[test]
+fn sender_sends_diffs() {
+    let recipient: SocketAddr = "10.0.0.9:1".parse().unwrap();
+    let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:1".parse().unwrap();
+    let mut sender = PexSender::new();
+
+    assert_eq!(None, sender.message(&[recipient].into(), recipient));
+    let first = sender.message(&[recipient, a].into(), recipient).unwrap();
+    assert_eq!(
+        vec![(a, FLAG_CONNECTABLE)],
+        PexMessage::from_bytes(&first).unwrap().added
+    );
+    // Too soon for the next one
+    assert_eq!(None, sender.message(&[b].into(), recipient));
+
+    sender.last_sent = Some(Instant::now() - PEX_INTERVAL);
+    let second = PexMessage::from_bytes(&sender.message(&[b].into(), recipient).unwrap()).unwrap();
+    assert_eq!(vec![(b, FLAG_CONNECTABLE)], second.added);
+    assert_eq!(vec![a], second.dropped);
+}
+
+#[test]
+fn handler_forwards_added_peers() {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut handler = PexHandler::new(tx);
+    let message = PexMessage {
+        added: vec![
+            ("10.0.0.1:6881".parse().unwrap(), 0),
+            ("0.0.0.0:6881".parse().unwrap(), 0),
+        ],
+        dropped: Vec::new(),
+    };
+    handler
+        .on_message(&message.to_bytes(), &mut Vec::new())
+        .unwrap();
+    assert_eq!(Ok("10.0.0.1:6881".parse().unwrap()), rx.try_recv());
+    assert!(rx.try_recv().is_err());
+}
@@ -0,0 +1,161 @@
+//! Piece selection strategies: which of the pieces a peer can give us to
+//! download next.
+
+use rand::seq::SliceRandom;
+
+use crate::bitfield::{Availability, Bitfield};
+use crate::torrent::Info;
+
+/// Chooses the next piece to download from a peer.
+pub trait PiecePicker: Send {
+    /// Picks one of `candidates`, the pieces the peer can send us right now
+    /// that nobody is downloading, in index order. `done` are the pieces we
+    /// already have and `availability` how many connected peers have each piece.
+    fn pick(
+        &mut self,
+        candidates: &[usize],
+        availability: &Availability,
+        done: &Bitfield,
+    ) -> Option<usize>;
+}
+
+/// Pieces in index order, for streaming.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, candidates: &[usize], _: &Availability, _: &Bitfield) -> Option<usize> {
+        candidates.first().copied()
+    }
+}
+
+/// The pieces fewest peers have first, so they spread before those peers
+/// leave. The very first pieces are random instead: any complete piece lets
+/// us start trading, and rare pieces are the slowest to get.
+#[derive(Debug, Clone, Copy)]
+pub struct RarestFirst {
+    /// Pieces picked at random before switching to rarest first
+    pub random_first: usize,
+}
+
+impl Default for RarestFirst {
+    fn default() -> Self {
+        Self { random_first: 4 }
+    }
+}
+
+impl PiecePicker for RarestFirst {
+    fn pick(
+        &mut self,
+        candidates: &[usize],
+        availability: &Availability,
+        done: &Bitfield,
+    ) -> Option<usize> {
+        if done.count_ones() < self.random_first {
+            return candidates.choose(&mut rand::thread_rng()).copied();
+        }
+        rarest(candidates.iter().copied(), availability)
+    }
+}
+
+/// Higher priority pieces first, the rarest among equals. Pieces of priority
+/// 0 still get downloaded, after all the others.
+#[derive(Debug, Clone)]
+pub struct Priority {
+    priorities: Vec<u8>,
+}
+
+impl Priority {
+    /// One priority per piece.
+    pub fn new(priorities: Vec<u8>) -> Self {
+        Self { priorities }
+    }
+
+    /// One priority per file; a piece gets the highest of the files it covers.
+    pub fn for_files(info: &Info, file_priorities: &[u8]) -> Self {
+        let priorities = (0..info.num_pieces())
+            .map(|piece| {
+                info.piece_spans(piece)
+                    .iter()
+                    .map(|span| file_priorities.get(span.file_index).copied().unwrap_or(0))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        Self::new(priorities)
+    }
+}
+
+impl PiecePicker for Priority {
+    fn pick(
+        &mut self,
+        candidates: &[usize],
+        availability: &Availability,
+        _: &Bitfield,
+    ) -> Option<usize> {
+        let priority = |piece: usize| self.priorities.get(piece).copied().unwrap_or(0);
+        let highest = candidates.iter().map(|&piece| priority(piece)).max()?;
+        rarest(
+            candidates
+                .iter()
+                .copied()
+                .filter(|&piece| priority(piece) == highest),
+            availability,
+        )
+    }
+}
+
+/// The least available of `pieces`, at random among ties so peers don't all
+/// go for the same one.
+fn rarest(pieces: impl Iterator<Item = usize>, availability: &Availability) -> Option<usize> {
+    let pieces: Vec<usize> = pieces.collect();
+    let fewest = pieces
+        .iter()
+        .map(|&piece| availability.count(piece))
+        .min()?;
+    let rarest: Vec<usize> = pieces
+        .into_iter()
+        .filter(|&piece| availability.count(piece) == fewest)
+        .collect();
+    rarest.choose(&mut rand::thread_rng()).copied()
+}
+
+#[test]
+fn pickers_choose_as_documented() {
+    let mut availability = Availability::new(6);
+    availability.add(&Bitfield::full(6));
+    availability.add(&Bitfield::full(6));
+    [0, 1, 3, 5].iter().for_each(|&i| availability.add_piece(i));
+    // Pieces 2 and 4 are the rarest
+    let candidates = [1, 2, 4, 5];
+    let mut done = Bitfield::new(6);
+
+    assert_eq!(Some(1), Sequential.pick(&candidates, &availability, &done));
+
+    let mut rarest_first = RarestFirst::default();
+    let first = rarest_first
+        .pick(&candidates, &availability, &done)
+        .unwrap();
+    assert!(candidates.contains(&first));
+    (0..4).for_each(|i| done.set(i, true));
+    for _ in 0..20 {
+        let piece = rarest_first.pick(&candidates, &availability, &done);
+        assert!(matches!(piece, Some(2 | 4)), "{:?}", piece);
+    }
+
+    let mut priority = Priority::new(vec![0, 3, 1, 0, 1, 3]);
+    assert_eq!(Some(1), priority.pick(&[1, 2, 3], &availability, &done));
+    // Pieces 2 and 4 share the highest priority, 0 comes last
+    availability.add_piece(2);
+    assert_eq!(Some(4), priority.pick(&[0, 2, 3, 4], &availability, &done));
+    assert_eq!(Some(2), priority.pick(&[0, 2], &availability, &done));
+    assert_eq!(None, priority.pick(&[], &availability, &done));
+}
+
+#[test]
+fn file_priorities_cover_pieces() {
+    let torrent = crate::torrent::multi_file_torrent();
+    // Piece 1 straddles both files and takes the higher priority
+    let priority = Priority::for_files(&torrent.info, &[1, 0, 2]);
+    assert_eq!(vec![1, 2, 2], priority.priorities);
+}
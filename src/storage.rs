@@ -0,0 +1,76 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::torrent::{Info, Keys};
+
+/// Writes verified pieces to the files they belong to.
+pub struct Storage {
+    info: Info,
+    paths: Vec<PathBuf>,
+}
+
+impl Storage {
+    /// Creates every file of the torrent with its final size.
+    /// A single-file torrent is written to `output` itself, a multi-file torrent
+    /// to a directory called `name` inside `output`, as other clients do.
+    pub fn create(info: &Info, output: &Path) -> Result<Self> {
+        let paths: Vec<PathBuf> = match info.keys {
+            Keys::SingleFile { .. } => vec![output.to_path_buf()],
+            Keys::MultiFile { .. } => {
+                let root = output.join(&info.name);
+                info.files().iter().map(|f| root.join(&f.path)).collect()
+            }
+        };
+
+        for (path, file) in paths.iter().zip(info.files()) {
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("creating directory {}", parent.display()))?;
+            }
+            let handle = fs::File::create(path)
+                .with_context(|| format!("creating output file {}", path.display()))?;
+            handle
+                .set_len(file.length as u64)
+                .with_context(|| format!("allocating output file {}", path.display()))?;
+        }
+
+        Ok(Self {
+            info: info.clone(),
+            paths,
+        })
+    }
+
+    pub fn write_piece(&self, piece_index: usize, piece: &[u8]) -> Result<()> {
+        for span in self.info.piece_spans(piece_index) {
+            let path = &self.paths[span.file_index];
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .with_context(|| format!("opening output file {}", path.display()))?;
+            file.seek(SeekFrom::Start(span.file_offset as u64))?;
+            file.write_all(&piece[span.piece_offset..span.piece_offset + span.length])
+                .context("Writing to output file failed")?;
+            file.flush().context("Output file flush failed")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn writes_pieces_across_files() {
+    let torrent = crate::torrent::multi_file_torrent();
+    let root = std::env::temp_dir().join(format!("torrust-storage-{}", std::process::id()));
+
+    let storage = Storage::create(&torrent.info, &root).unwrap();
+    storage.write_piece(1, b"5678").unwrap();
+    storage.write_piece(2, b"9abc").unwrap();
+    storage.write_piece(0, b"1234").unwrap();
+
+    assert_eq!(b"12345", &fs::read(root.join("dir/a/b.txt")).unwrap()[..]);
+    assert!(fs::read(root.join("dir/empty")).unwrap().is_empty());
+    assert_eq!(b"6789abc", &fs::read(root.join("dir/c")).unwrap()[..]);
+    fs::remove_dir_all(root).unwrap();
+}
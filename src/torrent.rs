@@ -1,30 +1,251 @@
-use anyhow::Result;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
 use pieces::Pieces;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use sha1::{Digest, Sha1};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Torrent {
-    pub announce: String,
+    pub announce: Option<String>,
+    /// Tiers of tracker URLs (BEP 12)
+    #[serde(default, rename = "announce-list")]
+    pub announce_list: Vec<Vec<String>>,
+    /// DHT nodes to bootstrap from, for trackerless torrents (BEP 5)
+    #[serde(default)]
+    pub nodes: Vec<(String, u16)>,
     pub info: Info,
+    /// The `info` dictionary exactly as it appears in the metainfo file
+    #[serde(skip)]
+    info_bytes: Vec<u8>,
+    #[serde(skip)]
+    info_hash: [u8; 20],
 }
 
 impl Torrent {
-    pub fn info_hash(&self) -> Result<[u8; 20]> {
+    /// Parses a metainfo file, keeping the raw `info` dictionary so the info hash
+    /// covers every key, including the ones `Info` doesn't model.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let info_bytes = raw_info(bytes)?;
+        let mut torrent: Torrent =
+            serde_bencode::from_bytes(bytes).context("parsing metainfo file")?;
+        torrent.info.validate()?;
+
         let mut hasher = Sha1::new();
-        let encoded = serde_bencode::to_bytes(&self.info)?;
-        hasher.update(&encoded);
-        Ok(hasher.finalize().try_into()?)
+        hasher.update(info_bytes);
+        torrent.info_hash = hasher.finalize().into();
+        torrent.info_bytes = info_bytes.to_vec();
+        Ok(torrent)
+    }
+
+    /// Builds a torrent from an info dictionary fetched through a magnet link.
+    pub fn from_info_bytes(info_bytes: &[u8], trackers: &[String]) -> Result<Self> {
+        let info: Info =
+            serde_bencode::from_bytes(info_bytes).context("parsing info dictionary")?;
+        info.validate()?;
+        Ok(Self {
+            announce: None,
+            announce_list: trackers.iter().map(|t| vec![t.clone()]).collect(),
+            nodes: Vec::new(),
+            info,
+            info_bytes: info_bytes.to_vec(),
+            info_hash: Sha1::digest(info_bytes).into(),
+        })
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    /// Tracker tiers to announce to. Per BEP 12, `announce` is only used when
+    /// there is no `announce-list`.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        if self.announce_list.iter().any(|tier| !tier.is_empty()) {
+            return self.announce_list.clone();
+        }
+        self.announce.iter().map(|url| vec![url.clone()]).collect()
+    }
+
+    /// Nodes from the metainfo followed by the well known DHT routers, as `host:port`.
+    pub fn dht_bootstrap_nodes(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .map(|(host, port)| format!("{}:{}", host, port))
+            .chain(crate::dht::BOOTSTRAP_NODES.iter().map(|n| n.to_string()))
+            .collect()
+    }
+
+    /// The bencoded `info` dictionary the info hash was computed from.
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info_bytes
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+fn raw_info(bytes: &[u8]) -> Result<&[u8]> {
+    crate::raw_dict_value(bytes, b"info")
+        .context("parsing metainfo file")?
+        .context("metainfo file has no info dictionary")
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Info {
-    pub length: usize,
     pub name: String,
     #[serde(rename = "piece length")]
     pub plength: usize,
     pub pieces: Pieces,
+    /// Set to 1 for private torrents (BEP 27)
+    #[serde(default)]
+    pub private: Option<i64>,
+    #[serde(flatten)]
+    pub keys: Keys,
+}
+
+/// Single-file torrents carry a `length`, multi-file torrents a `files` list
+/// whose paths are relative to a directory called `name`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Keys {
+    SingleFile { length: usize },
+    MultiFile { files: Vec<File> },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct File {
+    pub length: usize,
+    pub path: Vec<String>,
+}
+
+/// A file of the torrent, laid out in the concatenated piece data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    /// Path relative to the download root: the file name for single-file
+    /// torrents, the `path` components for multi-file ones.
+    pub path: PathBuf,
+    pub length: usize,
+    /// Offset of the first byte of the file in the torrent data
+    pub offset: usize,
+}
+
+/// The part of a piece that falls into one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSpan {
+    pub file_index: usize,
+    pub file_offset: usize,
+    pub piece_offset: usize,
+    pub length: usize,
+}
+
+impl Info {
+    /// Total length of all files.
+    pub fn length(&self) -> usize {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+
+    /// Private torrents may only get peers from their trackers,
+    /// never from PEX, the DHT or local discovery.
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.0.len()
+    }
+
+    /// Size of the given piece, the last one is usually shorter.
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        self.plength
+            .min(self.length().saturating_sub(self.plength * piece_index))
+    }
+
+    pub fn files(&self) -> Vec<TorrentFile> {
+        match &self.keys {
+            Keys::SingleFile { length } => vec![TorrentFile {
+                path: PathBuf::from(&self.name),
+                length: *length,
+                offset: 0,
+            }],
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|f| {
+                        let file = TorrentFile {
+                            path: f.path.iter().collect(),
+                            length: f.length,
+                            offset,
+                        };
+                        offset += f.length;
+                        file
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Maps a piece to the files it covers, in order.
+    /// Zero-length files never appear in a span.
+    pub fn piece_spans(&self, piece_index: usize) -> Vec<FileSpan> {
+        let start = self.plength * piece_index;
+        let end = start + self.piece_size(piece_index);
+        self.files()
+            .iter()
+            .enumerate()
+            .filter_map(|(file_index, file)| {
+                let span_start = start.max(file.offset);
+                let span_end = end.min(file.offset + file.length);
+                (span_start < span_end).then(|| FileSpan {
+                    file_index,
+                    file_offset: span_start - file.offset,
+                    piece_offset: span_start - start,
+                    length: span_end - span_start,
+                })
+            })
+            .collect()
+    }
+
+    /// Rejects metainfo that would make us misbehave: a piece count that doesn't
+    /// match the length, or file paths escaping the download directory.
+    fn validate(&self) -> Result<()> {
+        if self.plength == 0 {
+            bail!("piece length is zero");
+        }
+        if self.num_pieces() != self.length().div_ceil(self.plength) {
+            bail!(
+                "{} piece hashes for {} bytes in pieces of {}",
+                self.num_pieces(),
+                self.length(),
+                self.plength
+            );
+        }
+        if !is_safe_path(Path::new(&self.name)) || self.name.is_empty() {
+            bail!("invalid torrent name {:?}", self.name);
+        }
+        if let Keys::MultiFile { files } = &self.keys {
+            for file in files {
+                let safe = !file.path.is_empty()
+                    && file
+                        .path
+                        .iter()
+                        .all(|c| !c.is_empty() && is_safe_path(Path::new(c)));
+                if !safe {
+                    bail!("invalid file path {:?}", file.path);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A path component from the metainfo must name exactly one normal entry.
+fn is_safe_path(component: &Path) -> bool {
+    let mut components = component.components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !component.to_string_lossy().contains(['/', '\\'])
 }
 
 mod pieces {
@@ -51,7 +272,7 @@ mod pieces {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom("length is not correct"));
             }
             Ok(Pieces(
@@ -81,3 +302,64 @@ mod pieces {
         }
     }
 }
+
+#[test]
+fn info_hash_of_sample() {
+    let torrent = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+    assert_eq!(
+        "d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+        hex::encode(torrent.info_hash())
+    );
+}
+
+#[test]
+fn info_hash_covers_unmodelled_keys() {
+    let info =
+        b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee";
+    let mut file = b"d8:announce3:url4:info".to_vec();
+    file.extend_from_slice(info);
+    file.push(b'e');
+
+    let torrent = Torrent::from_bytes(&file).unwrap();
+    assert_eq!(&info[..], torrent.info_bytes());
+    let expected: [u8; 20] = Sha1::digest(info).into();
+    assert_eq!(expected, torrent.info_hash());
+}
+
+/// A torrent named `dir` with files `a/b.txt` (5 bytes), `empty` and `c`
+/// (7 bytes), in three pieces of 4 bytes.
+#[cfg(test)]
+pub(crate) fn multi_file_torrent() -> Torrent {
+    let file: &[u8] = b"d8:announce3:url4:infod5:filesld6:lengthi5e4:pathl1:a5:b.txteed6:lengthi0e4:pathl5:emptyeed6:lengthi7e4:pathl1:ceee4:name3:dir12:piece lengthi4e6:pieces60:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbccccccccccccccccccccee";
+    Torrent::from_bytes(file).unwrap()
+}
+
+#[test]
+fn multi_file_layout() {
+    let torrent = multi_file_torrent();
+    let info = &torrent.info;
+    assert_eq!(12, info.length());
+    assert_eq!(
+        vec![PathBuf::from("a/b.txt"), "empty".into(), "c".into()],
+        info.files().into_iter().map(|f| f.path).collect::<Vec<_>>()
+    );
+
+    let span = |file_index, file_offset, piece_offset, length| FileSpan {
+        file_index,
+        file_offset,
+        piece_offset,
+        length,
+    };
+    assert_eq!(vec![span(0, 0, 0, 4)], info.piece_spans(0));
+    assert_eq!(
+        vec![span(0, 4, 0, 1), span(2, 0, 1, 3)],
+        info.piece_spans(1)
+    );
+    assert_eq!(vec![span(2, 3, 0, 4)], info.piece_spans(2));
+}
+
+#[test]
+fn rejects_path_traversal() {
+    let file = b"d8:announce3:url4:infod5:filesld6:lengthi1e4:pathl2:..6:passwdeee4:name3:dir12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    assert!(Torrent::from_bytes(file).is_err());
+}
@@ -1,8 +1,33 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use peers::{Peers, Peers6};
+use udp::UdpTracker;
+
+use crate::BencodeValue;
 
-use peers::Peers;
+pub mod udp;
 
-#[derive(Debug, Serialize)]
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(15);
+/// UDP announces retransmit for up to an hour (BEP 15); past the first
+/// couple of tries the next tracker of the tier gets its turn.
+const UDP_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(60);
+/// First delay before retrying when every tracker failed, doubled on each failure.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How long `stopped` may take before we give up on telling the tracker.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
     pub peer_id: String,
     pub port: u16,
@@ -10,6 +35,23 @@ pub struct TrackerRequest {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<u32>,
+    /// Lets the tracker recognize us when our IP changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
 }
 
 pub fn hash_encoder(t: &[u8; 20]) -> String {
@@ -21,52 +63,500 @@ pub fn hash_encoder(t: &[u8; 20]) -> String {
     encoded
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct TrackerResponse {
     pub interval: usize,
-    pub peers: Peers,
+    pub min_interval: Option<usize>,
+    /// To be sent back on the next announces to the same tracker
+    pub tracker_id: Option<String>,
+    /// The announce succeeded but the tracker wants the user to know something
+    pub warning: Option<String>,
+    /// Number of seeders
+    pub complete: Option<u32>,
+    /// Number of leechers
+    pub incomplete: Option<u32>,
+    /// IPv4 and IPv6 peers
+    pub peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("tracker refused the announce: {0}")]
+    Failure(String),
+    #[error("invalid tracker response: {0}")]
+    InvalidResponse(String),
+}
+
+#[derive(Deserialize)]
+struct RawTrackerResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    interval: Option<usize>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<usize>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    complete: Option<u32>,
+    incomplete: Option<u32>,
+    #[serde(default)]
+    peers: Peers,
+    #[serde(default)]
+    peers6: Peers6,
+}
+
+impl TrackerResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TrackerError> {
+        let raw: RawTrackerResponse = serde_bencode::from_bytes(bytes)
+            .map_err(|e| TrackerError::InvalidResponse(e.to_string()))?;
+        if let Some(reason) = raw.failure_reason {
+            return Err(TrackerError::Failure(reason));
+        }
+        let interval = raw
+            .interval
+            .ok_or_else(|| TrackerError::InvalidResponse("missing interval".to_string()))?;
+        let mut peers = raw.peers.0;
+        peers.extend(raw.peers6.0);
+        Ok(Self {
+            interval,
+            min_interval: raw.min_interval,
+            tracker_id: raw.tracker_id,
+            warning: raw.warning_message,
+            complete: raw.complete,
+            incomplete: raw.incomplete,
+            peers,
+        })
+    }
+
+    /// When to announce next: `interval`, but never sooner than `min interval`.
+    pub fn reannounce_after(&self) -> Duration {
+        let secs = self.interval.max(self.min_interval.unwrap_or(0)).max(1);
+        Duration::from_secs(secs as u64)
+    }
+}
+
+/// Swarm statistics for one torrent, as reported by a tracker scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Peers with the whole torrent (seeders)
+    pub complete: u32,
+    /// Number of times the torrent has been fully downloaded
+    pub downloaded: u32,
+    /// Peers still downloading (leechers)
+    pub incomplete: u32,
+}
+
+/// Derives the scrape URL of an HTTP tracker: the last path segment must start
+/// with `announce`, which is replaced by `scrape`. Returns `None` for trackers
+/// that don't support scraping.
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    let (base, last) = announce_url.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    Some(format!("{}/scrape{}", base, rest))
+}
+
+/// Asks a tracker for the swarm statistics of several torrents at once.
+/// Torrents the tracker doesn't know are missing from the result.
+pub async fn scrape(url: &str, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let mut stats = HashMap::new();
+    if url.starts_with("udp://") {
+        let mut tracker = UdpTracker::connect(url).await?;
+        for batch in info_hashes.chunks(udp::MAX_SCRAPE_HASHES) {
+            let batch_stats = tracker.scrape(batch).await?;
+            stats.extend(batch.iter().copied().zip(batch_stats));
+        }
+        return Ok(stats);
+    }
+
+    let scrape_url = scrape_url(url).with_context(|| format!("{} does not support scrape", url))?;
+    let client = reqwest::Client::builder()
+        .timeout(ANNOUNCE_TIMEOUT)
+        .build()?;
+    for batch in info_hashes.chunks(udp::MAX_SCRAPE_HASHES) {
+        let query = batch
+            .iter()
+            .map(|h| format!("info_hash={}", hash_encoder(h)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let separator = if scrape_url.contains('?') { '&' } else { '?' };
+        let response = client
+            .get(format!("{}{}{}", scrape_url, separator, query))
+            .send()
+            .await?
+            .error_for_status()?;
+        let response = response.bytes().await?;
+        stats.extend(parse_scrape_response(&response)?);
+    }
+    Ok(stats)
+}
+
+/// Parses the `files` dictionary of an HTTP scrape response, keyed by raw info hash.
+fn parse_scrape_response(response: &[u8]) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let (response, _) = crate::decode_bencoded_value(response)?;
+    if let Some(reason) = response.get("failure reason").and_then(|r| r.as_bytes()) {
+        anyhow::bail!(
+            "tracker refused scrape: {}",
+            String::from_utf8_lossy(reason)
+        );
+    }
+    let files = response
+        .get("files")
+        .and_then(|f| f.as_dict())
+        .context("scrape response has no files dictionary")?;
+
+    let count = |stats: &BencodeValue, key| {
+        stats
+            .get(key)
+            .and_then(|v| v.as_int())
+            .map_or(0, |n| n.clamp(0, u32::MAX as i64) as u32)
+    };
+    files
+        .iter()
+        .map(|(hash, stats)| {
+            let hash: [u8; 20] = hash
+                .as_slice()
+                .try_into()
+                .context("scrape response has an invalid info hash")?;
+            Ok((
+                hash,
+                ScrapeStats {
+                    complete: count(stats, "complete"),
+                    downloaded: count(stats, "downloaded"),
+                    incomplete: count(stats, "incomplete"),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Sends a single announce to an HTTP or UDP tracker.
+pub async fn announce(
+    url: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> Result<TrackerResponse> {
+    if url.starts_with("udp://") {
+        let mut tracker = UdpTracker::connect(url).await?;
+        return tracker.announce(info_hash, request).await;
+    }
+    let query = serde_urlencoded::to_string(request)?;
+    let url = format!("{}?{}&info_hash={}", url, query, hash_encoder(info_hash));
+    let client = reqwest::Client::builder()
+        .timeout(ANNOUNCE_TIMEOUT)
+        .build()?;
+    let response = client.get(url).send().await?.error_for_status()?;
+    let response = response.bytes().await?;
+    Ok(TrackerResponse::from_bytes(&response)?)
+}
+
+/// Announces to the trackers of a torrent following BEP 12: tiers are tried in
+/// order, trackers within a tier in random order, and a tracker that responds is
+/// moved to the front of its tier so it is tried first next time.
+pub struct Announcer {
+    tiers: Vec<Vec<String>>,
+    tracker_ids: HashMap<String, String>,
+    udp_trackers: HashMap<String, UdpTracker>,
+}
+
+impl Announcer {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rng);
+        }
+        tiers.retain(|tier| !tier.is_empty());
+        Self {
+            tiers,
+            tracker_ids: HashMap::new(),
+            udp_trackers: HashMap::new(),
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        let mut last_error = anyhow!("torrent has no trackers");
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let url = &tier[i];
+                let result = if url.starts_with("udp://") {
+                    let udp_trackers = &mut self.udp_trackers;
+                    let announce = async {
+                        // UDP clients are kept so their connection id is reused
+                        let tracker = match udp_trackers.get_mut(url) {
+                            Some(tracker) => tracker,
+                            None => udp_trackers
+                                .entry(url.clone())
+                                .or_insert(UdpTracker::connect(url).await?),
+                        };
+                        tracker.announce(info_hash, request).await
+                    };
+                    tokio::time::timeout(UDP_ANNOUNCE_TIMEOUT, announce)
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow!("{} timed out", url)))
+                } else {
+                    let mut request = request.clone();
+                    request.trackerid = self.tracker_ids.get(url).cloned();
+                    announce(url, info_hash, &request).await
+                };
+                match result {
+                    Ok(response) => {
+                        if let Some(tracker_id) = &response.tracker_id {
+                            self.tracker_ids.insert(url.clone(), tracker_id.clone());
+                        }
+                        tier[..=i].rotate_right(1);
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        eprintln!("Tracker {} failed: {:#}", tier[i], e);
+                        last_error = e.context(format!("announcing to {}", tier[i]));
+                    }
+                }
+            }
+        }
+        Err(last_error).context("every tracker failed")
+    }
+}
+
+/// Transfer counters reported to trackers, shared with the download.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: usize) -> Self {
+        Self {
+            left: AtomicU64::new(left as u64),
+            ..Default::default()
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records verified data, which is also no longer left to download.
+    pub fn add_downloaded(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes as u64))
+            });
+    }
+
+    pub fn uploaded(&self) -> usize {
+        self.uploaded.load(Ordering::Relaxed) as usize
+    }
+
+    pub fn downloaded(&self) -> usize {
+        self.downloaded.load(Ordering::Relaxed) as usize
+    }
+
+    pub fn left(&self) -> usize {
+        self.left.load(Ordering::Relaxed) as usize
+    }
+}
+
+/// Settings for the announces a [`AnnounceTask`] sends.
+#[derive(Debug, Clone)]
+pub struct AnnounceConfig {
+    pub peer_id: String,
+    pub port: u16,
+    pub numwant: Option<u32>,
+}
+
+enum AnnounceCommand {
+    Completed,
+    Stop,
+}
+
+/// Handle to a background task that announces `started` right away,
+/// re-announces on the tracker's interval, and sends `completed` and
+/// `stopped` when told to. Discovered peers are sent to the given channel.
+pub struct AnnounceTask {
+    commands: mpsc::UnboundedSender<AnnounceCommand>,
+    task: JoinHandle<()>,
+}
+
+impl AnnounceTask {
+    pub fn spawn(
+        mut announcer: Announcer,
+        info_hash: [u8; 20],
+        config: AnnounceConfig,
+        stats: Arc<TransferStats>,
+        peers: mpsc::UnboundedSender<SocketAddr>,
+    ) -> Self {
+        let (commands, mut command_rx) = mpsc::unbounded_channel();
+        let key = rand::random();
+        let task = tokio::spawn(async move {
+            let request = |event| TrackerRequest {
+                peer_id: config.peer_id.clone(),
+                port: config.port,
+                uploaded: stats.uploaded(),
+                downloaded: stats.downloaded(),
+                left: stats.left(),
+                compact: 1,
+                event,
+                numwant: config.numwant,
+                key: Some(key),
+                trackerid: None,
+            };
+
+            // An event is kept until a tracker has acknowledged it
+            let mut pending = Some(Event::Started);
+            // `completed` is only sent once `started` went through
+            let mut completed = false;
+            let mut retry = RETRY_DELAY;
+            loop {
+                let wait = match announcer.announce(&info_hash, &request(pending)).await {
+                    Ok(response) => {
+                        pending = std::mem::take(&mut completed).then_some(Event::Completed);
+                        retry = RETRY_DELAY;
+                        if let Some(warning) = &response.warning {
+                            eprintln!("Tracker warning: {}", warning);
+                        }
+                        for &peer in &response.peers {
+                            let _ = peers.send(peer);
+                        }
+                        if pending.is_some() {
+                            Duration::ZERO
+                        } else {
+                            response.reannounce_after()
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Announce failed: {:#}", e);
+                        let wait = retry;
+                        retry = (retry * 2).min(Duration::from_secs(3600));
+                        wait
+                    }
+                };
+
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    command = command_rx.recv() => match command {
+                        Some(AnnounceCommand::Completed) if pending == Some(Event::Started) => {
+                            completed = true;
+                        }
+                        Some(AnnounceCommand::Completed) => pending = Some(Event::Completed),
+                        Some(AnnounceCommand::Stop) | None => break,
+                    },
+                }
+            }
+
+            // Only tell trackers we're leaving if they know about us
+            if pending != Some(Event::Started) {
+                let stopped = request(Some(Event::Stopped));
+                let _ =
+                    tokio::time::timeout(STOP_TIMEOUT, announcer.announce(&info_hash, &stopped))
+                        .await;
+            }
+        });
+        Self { commands, task }
+    }
+
+    /// Announces `completed` now; call once all pieces are verified.
+    pub fn completed(&self) {
+        let _ = self.commands.send(AnnounceCommand::Completed);
+    }
+
+    /// Announces `stopped` and waits for the task to finish.
+    pub async fn stop(self) {
+        let _ = self.commands.send(AnnounceCommand::Stop);
+        let _ = self.task.await;
+    }
 }
 
 mod peers {
     use std::{
         fmt,
-        net::{Ipv4Addr, SocketAddrV4},
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     };
 
     use serde::{
-        de::{self, Visitor},
+        de::{self, SeqAccess, Visitor},
         Deserialize, Deserializer,
     };
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    /// The `peers` key: either the compact byte string of 6-byte IPv4 entries,
+    /// or a list of dictionaries with `ip` and `port`.
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers(pub Vec<SocketAddr>);
 
-    struct PeerVisitor;
+    /// The `peers6` key (BEP 7): a compact byte string of 18-byte IPv6 entries.
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers6(pub Vec<SocketAddr>);
+
+    /// Parses compact peers of 6 (IPv4) or 18 (IPv6) bytes each.
+    pub fn compact_peers(v: &[u8], width: usize) -> Option<Vec<SocketAddr>> {
+        if !v.len().is_multiple_of(width) {
+            return None;
+        }
+        Some(
+            v.chunks_exact(width)
+                .map(|s| {
+                    let (ip, port) = s.split_at(width - 2);
+                    let ip: IpAddr = match ip.len() {
+                        4 => Ipv4Addr::from(<[u8; 4]>::try_from(ip).expect("length is 4")).into(),
+                        _ => Ipv6Addr::from(<[u8; 16]>::try_from(ip).expect("length is 16")).into(),
+                    };
+                    SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+                })
+                .collect(),
+        )
+    }
+
+    #[derive(Deserialize)]
+    struct DictPeer {
+        ip: String,
+        port: u16,
+    }
+
+    struct PeerVisitor {
+        width: usize,
+    }
 
     impl<'de> Visitor<'de> for PeerVisitor {
-        type Value = Peers;
+        type Value = Vec<SocketAddr>;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a byte string with length multiple of 20")
+            write!(
+                formatter,
+                "a byte string with length multiple of {} or a list of peer dictionaries",
+                self.width
+            )
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
-                return Err(E::custom("length is not correct"));
+            compact_peers(v, self.width).ok_or_else(|| E::custom("length is not correct"))
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Vec::new();
+            while let Some(peer) = seq.next_element::<DictPeer>()? {
+                // Host names aren't resolved, trackers hardly ever send them
+                if let Ok(ip) = peer.ip.parse::<IpAddr>() {
+                    peers.push(SocketAddr::new(ip, peer.port));
+                }
             }
-            Ok(Peers(
-                v.chunks_exact(6)
-                    .map(|s| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(s[0], s[1], s[2], s[3]),
-                            u16::from_be_bytes([s[4], s[5]]),
-                        )
-                    })
-                    .collect(),
-            ))
+            Ok(peers)
         }
     }
 
@@ -75,7 +565,234 @@ mod peers {
         where
             D: Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(PeerVisitor)
+            deserializer
+                .deserialize_bytes(PeerVisitor { width: 6 })
+                .map(Peers)
         }
     }
+
+    impl<'de> Deserialize<'de> for Peers6 {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer
+                .deserialize_bytes(PeerVisitor { width: 18 })
+                .map(Peers6)
+        }
+    }
+}
+
+#[tokio::test]
+async fn announcer_fails_over_and_promotes() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let live = format!("http://{}/announce", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let body = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    });
+
+    let dead = "http://127.0.0.1:1/announce".to_string();
+    let mut announcer = Announcer::new(vec![vec![dead.clone()], vec![dead, live.clone()]]);
+    let request = TrackerRequest {
+        peer_id: String::from("00112233445566778899"),
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 1,
+        compact: 1,
+        event: None,
+        numwant: None,
+        key: None,
+        trackerid: None,
+    };
+    let response = announcer.announce(&[0; 20], &request).await.unwrap();
+    assert_eq!(
+        vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()],
+        response.peers
+    );
+    assert_eq!(live, announcer.tiers()[1][0]);
+}
+
+#[tokio::test]
+async fn announce_task_sends_lifecycle_events() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let (requests_tx, mut requests) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let len = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..len]).to_string();
+            requests_tx.send(request).unwrap();
+            let body = b"d8:intervali1800e10:tracker id3:abc5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    });
+
+    let stats = Arc::new(TransferStats::new(100));
+    let (peers_tx, mut peers) = mpsc::unbounded_channel();
+    let config = AnnounceConfig {
+        peer_id: String::from("00112233445566778899"),
+        port: 6881,
+        numwant: Some(30),
+    };
+    let task = AnnounceTask::spawn(
+        Announcer::new(vec![vec![url]]),
+        [0; 20],
+        config,
+        stats.clone(),
+        peers_tx,
+    );
+
+    let started = requests.recv().await.unwrap();
+    assert!(started.contains("event=started"), "{}", started);
+    assert!(started.contains("left=100&"));
+    assert!(started.contains("numwant=30"));
+    assert!(!started.contains("trackerid"));
+    assert_eq!(
+        "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+        peers.recv().await.unwrap()
+    );
+
+    stats.add_downloaded(100);
+    task.completed();
+    let completed = requests.recv().await.unwrap();
+    assert!(completed.contains("event=completed"), "{}", completed);
+    assert!(completed.contains("downloaded=100&left=0&"));
+    assert!(completed.contains("trackerid=abc"));
+
+    task.stop().await;
+    let stopped = requests.recv().await.unwrap();
+    assert!(stopped.contains("event=stopped"), "{}", stopped);
+}
+
+#[tokio::test]
+async fn announce_task_sends_completed_after_started() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // The first announce fails, so `started` is still pending on completion
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let (requests_tx, mut requests) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for attempt in 0.. {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let len = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..len]).to_string();
+            requests_tx.send(request).unwrap();
+            let body: &[u8] = if attempt == 0 {
+                b"d14:failure reason4:busye"
+            } else {
+                b"d8:intervali1800e5:peers0:e"
+            };
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    });
+
+    let (peers_tx, _peers) = mpsc::unbounded_channel();
+    let config = AnnounceConfig {
+        peer_id: String::from("00112233445566778899"),
+        port: 6881,
+        numwant: None,
+    };
+    let task = AnnounceTask::spawn(
+        Announcer::new(vec![vec![url]]),
+        [0; 20],
+        config,
+        Arc::new(TransferStats::new(0)),
+        peers_tx,
+    );
+
+    let failed = requests.recv().await.unwrap();
+    assert!(failed.contains("event=started"), "{}", failed);
+    task.completed();
+    let started = requests.recv().await.unwrap();
+    assert!(started.contains("event=started"), "{}", started);
+    let completed = requests.recv().await.unwrap();
+    assert!(completed.contains("event=completed"), "{}", completed);
+
+    task.stop().await;
+    let stopped = requests.recv().await.unwrap();
+    assert!(stopped.contains("event=stopped"), "{}", stopped);
+}
+
+#[test]
+fn derives_scrape_url() {
+    assert_eq!(
+        Some("http://example.com/scrape".to_string()),
+        scrape_url("http://example.com/announce")
+    );
+    assert_eq!(
+        Some("http://example.com/x/scrape.php?k=1".to_string()),
+        scrape_url("http://example.com/x/announce.php?k=1")
+    );
+    assert_eq!(None, scrape_url("http://example.com/a"));
+    assert_eq!(None, scrape_url("http://example.com/x/announce/y"));
+}
+
+#[test]
+fn parses_scrape_response() {
+    let mut response = b"d5:filesd20:".to_vec();
+    response.extend_from_slice(&[7; 20]);
+    response.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+    let stats = parse_scrape_response(&response).unwrap();
+    assert_eq!(
+        Some(&ScrapeStats {
+            complete: 5,
+            downloaded: 50,
+            incomplete: 10
+        }),
+        stats.get(&[7; 20])
+    );
+}
+
+#[test]
+fn parses_tracker_responses() {
+    let failure = TrackerResponse::from_bytes(b"d14:failure reason12:unregisterede");
+    assert!(matches!(failure, Err(TrackerError::Failure(reason)) if reason == "unregistered"));
+
+    let mut compact = b"d8:completei3e10:incompletei4e8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe16:peers618:".to_vec();
+    compact.extend_from_slice(&[
+        0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe2,
+    ]);
+    compact.extend_from_slice(b"15:warning message4:slowe");
+    let response = TrackerResponse::from_bytes(&compact).unwrap();
+    assert_eq!(Some(3), response.complete);
+    assert_eq!(Some(4), response.incomplete);
+    assert_eq!(Some("slow".to_string()), response.warning);
+    assert_eq!(
+        vec![
+            "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "[2001:db8::1]:6882".parse().unwrap()
+        ],
+        response.peers
+    );
+
+    let dict = b"d8:intervali900e5:peersld2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip7:example4:porti1eed2:ip3:::14:porti2eeee";
+    let response = TrackerResponse::from_bytes(dict).unwrap();
+    assert_eq!(
+        vec![
+            "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "[::1]:2".parse().unwrap()
+        ],
+        response.peers
+    );
 }
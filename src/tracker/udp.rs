@@ -0,0 +1,343 @@
+//! UDP tracker protocol (BEP 15)
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use super::peers::compact_peers;
+use super::{Event, ScrapeStats, TrackerError, TrackerRequest, TrackerResponse};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// A connection id may be used for one minute after it was received.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// Most info hashes a single scrape request can hold.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    key: u32,
+    base_timeout: Duration,
+    max_retransmissions: u32,
+}
+
+impl UdpTracker {
+    /// Resolves a `udp://host:port` tracker URL and binds a local socket for it.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let url = Url::parse(url).context("parsing tracker url")?;
+        if url.scheme() != "udp" {
+            bail!("not a udp tracker url: {}", url);
+        }
+        let host = url.host_str().context("tracker url has no host")?;
+        let port = url.port().context("tracker url has no port")?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addr = tokio::net::lookup_host((host, port))
+            .await
+            .context("resolving tracker")?
+            .next()
+            .context("tracker host has no address")?;
+
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            socket,
+            connection: None,
+            key: rand::random(),
+            base_timeout: Duration::from_secs(15),
+            max_retransmissions: 8,
+        })
+    }
+
+    /// Overrides the retransmission schedule: a request is resent after
+    /// `base * 2^n` seconds, for n from 0 up to `max_retransmissions`, with
+    /// n capped at 8.
+    pub fn set_timeouts(&mut self, base: Duration, max_retransmissions: u32) {
+        self.base_timeout = base;
+        self.max_retransmissions = max_retransmissions;
+    }
+
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        let peer_id: &[u8; 20] = request
+            .peer_id
+            .as_bytes()
+            .try_into()
+            .context("peer id must be 20 bytes")?;
+
+        let mut body = BytesMut::with_capacity(82);
+        body.put_slice(info_hash);
+        body.put_slice(peer_id);
+        body.put_u64(request.downloaded as u64);
+        body.put_u64(request.left as u64);
+        body.put_u64(request.uploaded as u64);
+        body.put_u32(match request.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        });
+        // ip address: the one the packet comes from
+        body.put_u32(0);
+        body.put_u32(request.key.unwrap_or(self.key));
+        // -1 lets the tracker pick
+        body.put_i32(request.numwant.map_or(-1, |n| n as i32));
+        body.put_u16(request.port);
+
+        let response = self.transact(ACTION_ANNOUNCE, &body).await?;
+        let mut response = &response[..];
+        if response.len() < 12 {
+            bail!("announce response too short");
+        }
+        let interval = response.get_u32();
+        let leechers = response.get_u32();
+        let seeders = response.get_u32();
+        // Peers come in the address family of the tracker itself
+        let width = if self.socket.peer_addr()?.is_ipv4() {
+            6
+        } else {
+            18
+        };
+        let usable = response.len() - response.len() % width;
+        let peers = compact_peers(&response[..usable], width).expect("length is a multiple");
+        Ok(TrackerResponse {
+            interval: interval as usize,
+            min_interval: None,
+            tracker_id: None,
+            warning: None,
+            complete: Some(seeders),
+            incomplete: Some(leechers),
+            peers,
+        })
+    }
+
+    /// Scrapes up to [`MAX_SCRAPE_HASHES`] torrents, returning their stats in
+    /// the same order as `info_hashes`.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        if info_hashes.len() > MAX_SCRAPE_HASHES {
+            bail!("at most {} info hashes per scrape", MAX_SCRAPE_HASHES);
+        }
+        let body = info_hashes.concat();
+        let response = self.transact(ACTION_SCRAPE, &body).await?;
+        if response.len() < 12 * info_hashes.len() {
+            bail!("scrape response too short");
+        }
+        Ok(response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|mut s| ScrapeStats {
+                complete: s.get_u32(),
+                downloaded: s.get_u32(),
+                incomplete: s.get_u32(),
+            })
+            .collect())
+    }
+
+    /// Sends a request with a valid connection id, retransmitting on timeout.
+    /// Returns the response payload after the action and transaction id.
+    async fn transact(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        // One retransmission counter for the connect and the request (BEP 15)
+        for n in 0..=self.max_retransmissions {
+            // The wait stops growing at n = 8, where BEP 15 gives up
+            let wait = self.base_timeout.saturating_mul(2u32.pow(n.min(8)));
+            let Some(connection_id) = self.connection_id(wait).await? else {
+                continue;
+            };
+            if let Some(response) = self.exchange(connection_id, action, body, wait).await? {
+                return Ok(response);
+            }
+        }
+        bail!("tracker did not respond")
+    }
+
+    /// Returns the current connection id, running the connect exchange when
+    /// there is none or it has expired. `None` when that timed out after `wait`.
+    async fn connection_id(&mut self, wait: Duration) -> Result<Option<u64>> {
+        if let Some((id, obtained)) = self.connection {
+            if obtained.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(Some(id));
+            }
+        }
+
+        let obtained = Instant::now();
+        let Some(response) = self
+            .exchange(PROTOCOL_ID, ACTION_CONNECT, &[], wait)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let id = u64::from_be_bytes(
+            response
+                .get(..8)
+                .context("connect response too short")?
+                .try_into()?,
+        );
+        self.connection = Some((id, obtained));
+        Ok(Some(id))
+    }
+
+    /// One send and wait. Returns `None` on timeout so the caller can retransmit.
+    async fn exchange(
+        &self,
+        connection_id: u64,
+        action: u32,
+        body: &[u8],
+        wait: Duration,
+    ) -> Result<Option<Vec<u8>>> {
+        let transaction_id: u32 = rand::random();
+        let mut packet = BytesMut::with_capacity(16 + body.len());
+        packet.put_u64(connection_id);
+        packet.put_u32(action);
+        packet.put_u32(transaction_id);
+        packet.put_slice(body);
+        self.socket.send(&packet).await?;
+
+        let receive = async {
+            let mut buf = vec![0; 65536];
+            loop {
+                let len = self.socket.recv(&mut buf).await?;
+                let mut response = &buf[..len];
+                if response.len() < 8 {
+                    continue;
+                }
+                let response_action = response.get_u32();
+                // Stale replies to earlier transmissions are ignored
+                if response.get_u32() != transaction_id {
+                    continue;
+                }
+                if response_action == ACTION_ERROR {
+                    let reason = String::from_utf8_lossy(response);
+                    return Err(
+                        TrackerError::Failure(reason.trim_end_matches('\0').to_string()).into(),
+                    );
+                }
+                if response_action != action {
+                    bail!(
+                        "expected action {}, tracker sent {}",
+                        action,
+                        response_action
+                    );
+                }
+                return Ok(response.to_vec());
+            }
+        };
+        match timeout(wait, receive).await {
+            Ok(response) => response.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+#[tokio::test]
+async fn announce_and_scrape_against_local_tracker() {
+    let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}", tracker.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut buf = [0; 2048];
+        let mut dropped_first = false;
+        loop {
+            let (len, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut packet = &buf[..len];
+            // Drop the first packet to exercise retransmission
+            if !dropped_first {
+                dropped_first = true;
+                continue;
+            }
+            let connection_id = packet.get_u64();
+            let action = packet.get_u32();
+            let transaction_id = packet.get_u32();
+            let mut reply = BytesMut::new();
+            reply.put_u32(action);
+            reply.put_u32(transaction_id);
+            match action {
+                ACTION_CONNECT => {
+                    assert_eq!(PROTOCOL_ID, connection_id);
+                    reply.put_u64(0xdead_beef);
+                }
+                ACTION_ANNOUNCE => {
+                    assert_eq!(0xdead_beef, connection_id);
+                    assert_eq!(82, packet.len());
+                    reply.put_u32(1800);
+                    reply.put_u32(1);
+                    reply.put_u32(2);
+                    reply.put_slice(&[127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+                }
+                ACTION_SCRAPE => {
+                    for (i, _) in packet.chunks_exact(20).enumerate() {
+                        reply.put_u32(i as u32);
+                        reply.put_u32(10);
+                        reply.put_u32(20);
+                    }
+                }
+                _ => unreachable!(),
+            }
+            tracker.send_to(&reply, from).await.unwrap();
+        }
+    });
+
+    let mut client = UdpTracker::connect(&url).await.unwrap();
+    client.set_timeouts(Duration::from_millis(50), 3);
+    let request = TrackerRequest {
+        peer_id: String::from("00112233445566778899"),
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 1,
+        compact: 1,
+        event: Some(Event::Started),
+        numwant: Some(50),
+        key: None,
+        trackerid: None,
+    };
+    let response = client.announce(&[1; 20], &request).await.unwrap();
+    assert_eq!(1800, response.interval);
+    assert_eq!(
+        vec![
+            "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "10.0.0.2:6882".parse().unwrap()
+        ],
+        response.peers
+    );
+
+    let stats = client.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+    assert_eq!(2, stats.len());
+    assert_eq!(1, stats[1].complete);
+    assert_eq!(20, stats[1].incomplete);
+}
+
+#[tokio::test]
+async fn connect_and_request_share_retransmissions() {
+    let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}", tracker.local_addr().unwrap());
+
+    let mut client = UdpTracker::connect(&url).await.unwrap();
+    client.set_timeouts(Duration::from_millis(10), 2);
+    let error = client.scrape(&[[1; 20]]).await.unwrap_err();
+    assert_eq!("tracker did not respond", error.to_string());
+
+    // A silent tracker gets one connect per try, not a full round of them
+    let mut buf = [0; 2048];
+    let mut connects = 0;
+    while let Ok(Ok(_)) =
+        tokio::time::timeout(Duration::from_millis(50), tracker.recv_from(&mut buf)).await
+    {
+        connects += 1;
+    }
+    assert_eq!(3, connects);
+}
@@ -1,13 +1,1121 @@
-// TODO: Create a worker queue that will have all the parts
-#![allow(dead_code)]
+//! Downloads a torrent from many peers at once: every connection takes the
+//! pieces it can serve from a shared queue, and puts them back when it fails.
+//! Once every piece is being downloaded, idle connections join the pieces
+//! still in progress and request their missing blocks too (endgame). Peers
+//! sending corrupt data are banned.
 
-use crate::peer::Peer;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinSet;
+use tokio::time;
+
+use crate::bitfield::{Availability, Bitfield, SharedAvailability};
+use crate::extension::ExtendedHandshake;
+use crate::peer::{BlockInfo, Peer, PeerMessage};
+use crate::pex::{PexHandler, PexSender};
+use crate::picker::{PiecePicker, RarestFirst};
+use crate::torrent::{Info, Torrent};
+
+/// Largest block we request, the most peers are willing to serve
+pub const BLOCK_MAX: u32 = 16384;
+/// Connections open at the same time
+const MAX_PEERS: usize = 30;
+/// Peers taking part in this many corrupt pieces are banned
+const MAX_HASH_FAILURES: usize = 3;
+/// Wait before connecting again to a peer that failed, doubled every time it fails again
+const RETRY_BACKOFF: Duration = Duration::from_secs(15);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10 * 60);
+/// How long to wait for new peers once no connection is left
+const NO_PEERS_GRACE: Duration = Duration::from_secs(2 * 60);
+/// How long a peer may go without any piece we need before its slot goes to
+/// another one
+const USELESS_PEER_GRACE: Duration = Duration::from_secs(60);
+
+/// A verified piece: its index and data.
+pub type Piece = (usize, Vec<u8>);
+
+struct Queue {
+    /// Pieces nobody is downloading yet, in order
+    pending: VecDeque<usize>,
+    /// Pieces being downloaded
+    active: HashMap<usize, Active>,
+    /// Pieces verified and handed over
+    done: Bitfield,
+    /// Peers we are connected to
+    connected: HashSet<SocketAddr>,
+    /// Chooses among the pending pieces a peer has
+    picker: Box<dyn PiecePicker>,
+    /// Copies of pieces that failed the hash check, until one passes
+    failed: HashMap<usize, Vec<FailedCopy>>,
+    /// Corrupt pieces each peer sent blocks of
+    failures: HashMap<SocketAddr, usize>,
+    banned: HashSet<SocketAddr>,
+}
+
+/// Who sent each block of a corrupt piece, with the block's hash.
+type FailedCopy = Vec<(SocketAddr, [u8; 20])>;
+
+impl Queue {
+    /// Whether the peer sent part of a corrupt copy of the piece.
+    fn sent_corrupt(&self, index: usize, peer: SocketAddr) -> bool {
+        self.failed
+            .get(&index)
+            .is_some_and(|copies| copies.iter().flatten().any(|&(from, _)| from == peer))
+    }
+}
+
+struct Active {
+    download: Arc<PieceDownload>,
+    /// Connections working on it
+    connections: usize,
+}
+
+struct Shared {
+    torrent: Arc<Torrent>,
+    queue: Mutex<Queue>,
+    /// Signalled when pieces return to the queue or the download completes
+    changed: Notify,
+    availability: SharedAvailability,
+    /// Where PEX sends the peers it learns about, unless the torrent is private
+    discovered: Option<mpsc::UnboundedSender<SocketAddr>>,
+}
+
+/// A piece a connection is downloading. It goes back to the queue when the
+/// last connection on it drops its assignment before [`Assignment::finish`],
+/// whatever made the connection stop.
+struct Assignment<'a> {
+    shared: &'a Shared,
+    download: Arc<PieceDownload>,
+    finished: bool,
+}
+
+impl Assignment<'_> {
+    fn finish(mut self) {
+        self.finished = true;
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.done.set(self.download.index, true);
+        queue.active.remove(&self.download.index);
+        if queue.done.all() {
+            self.shared.changed.notify_waiters();
+        }
+    }
+}
+
+impl Drop for Assignment<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let index = self.download.index;
+        let mut queue = self.shared.queue.lock().unwrap();
+        // Already finished by another connection in endgame
+        let Entry::Occupied(mut active) = queue.active.entry(index) else {
+            return;
+        };
+        active.get_mut().connections -= 1;
+        if active.get().connections == 0 {
+            active.remove();
+            // Back at its place, so the picker sees pieces in index order
+            let at = queue.pending.partition_point(|&i| i < index);
+            queue.pending.insert(at, index);
+            self.shared.changed.notify_waiters();
+        }
+    }
+}
+
+/// Downloads every piece of a torrent from the peers it is given.
 pub struct Worker {
-    queue: Vec<Part>,
-    peers: Vec<Peer>,
+    shared: Arc<Shared>,
+    /// Peers learned through PEX
+    discovered: mpsc::UnboundedReceiver<SocketAddr>,
+}
+
+impl Worker {
+    pub fn new(torrent: Arc<Torrent>) -> Self {
+        let num_pieces = torrent.info.num_pieces();
+        let (discovered_tx, discovered) = mpsc::unbounded_channel();
+        let discovered_tx = (!torrent.info.is_private()).then_some(discovered_tx);
+        Self {
+            discovered,
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue {
+                    pending: (0..num_pieces).collect(),
+                    active: HashMap::new(),
+                    done: Bitfield::new(num_pieces),
+                    connected: HashSet::new(),
+                    picker: Box::new(RarestFirst::default()),
+                    failed: HashMap::new(),
+                    failures: HashMap::new(),
+                    banned: HashSet::new(),
+                }),
+                torrent,
+                changed: Notify::new(),
+                availability: Availability::shared(num_pieces),
+                discovered: discovered_tx,
+            }),
+        }
+    }
+
+    /// Picks pieces with `picker` instead of [`RarestFirst`].
+    pub fn with_picker(self, picker: Box<dyn PiecePicker>) -> Self {
+        self.shared.queue.lock().unwrap().picker = picker;
+        self
+    }
+
+    /// Connects to the peers received on `peers` or learned through PEX, up
+    /// to [`MAX_PEERS`] at a time, and sends each verified piece to `pieces`.
+    /// Peers that fail, panic, or have no piece we need for
+    /// [`USELESS_PEER_GRACE`] are tried again after a backoff, unless banned.
+    /// Returns once every piece was sent, or fails when no peer is left to
+    /// try for [`NO_PEERS_GRACE`].
+    pub async fn run(
+        mut self,
+        mut peers: mpsc::UnboundedReceiver<SocketAddr>,
+        pieces: mpsc::Sender<Piece>,
+    ) -> Result<()> {
+        let mut tasks = JoinSet::new();
+        // Peer each task talks to, to know who a panicked task was about
+        let mut running = HashMap::new();
+        // Peers connected, waiting for a connection or for a retry
+        let mut known = HashSet::new();
+        let mut waiting = VecDeque::new();
+        let mut retries: Vec<(time::Instant, SocketAddr)> = Vec::new();
+        let mut failures: HashMap<SocketAddr, u32> = HashMap::new();
+        let mut peers_open = true;
+        let mut idle_since = None;
+
+        while !self.shared.is_complete() {
+            while tasks.len() < MAX_PEERS {
+                let Some(addr) = waiting.pop_front() else {
+                    break;
+                };
+                let shared = self.shared.clone();
+                let pieces = pieces.clone();
+                let task = tasks.spawn(async move { shared.run_peer(addr, pieces).await });
+                running.insert(task.id(), addr);
+            }
+
+            // Tasks are only missing with nobody waiting for a connection
+            if !tasks.is_empty() {
+                idle_since = None;
+            } else if !peers_open && retries.is_empty()
+                || idle_since.get_or_insert_with(time::Instant::now).elapsed() >= NO_PEERS_GRACE
+            {
+                bail!("no peers left to download from");
+            }
+            let give_up = idle_since.map(|since| since + NO_PEERS_GRACE);
+            let next_retry = retries.iter().map(|&(at, _)| at).min();
+            let wake = next_retry.into_iter().chain(give_up).min();
+
+            tokio::select! {
+                addr = peers.recv(), if peers_open => match addr {
+                    Some(addr) if known.insert(addr) => waiting.push_back(addr),
+                    Some(_) => {}
+                    None => peers_open = false,
+                },
+                Some(addr) = self.discovered.recv() => {
+                    if known.insert(addr) {
+                        waiting.push_back(addr);
+                    }
+                }
+                Some(finished) = tasks.join_next_with_id() => {
+                    let (addr, error) = match finished {
+                        Ok((id, result)) => (running.remove(&id), result.err()),
+                        Err(e) => {
+                            let addr = running.remove(&e.id());
+                            // A panic skipped the cleanup of run_peer
+                            if let Some(addr) = addr {
+                                self.shared.queue.lock().unwrap().connected.remove(&addr);
+                            }
+                            (addr, Some(anyhow::Error::new(e)))
+                        }
+                    };
+                    if let (Some(addr), Some(e)) = (addr, error) {
+                        eprintln!("{}: {:#}", addr, e);
+                        // Banned peers stay known, so they never come back
+                        if !self.shared.queue.lock().unwrap().banned.contains(&addr) {
+                            let failed = failures.entry(addr).or_default();
+                            let backoff = RETRY_BACKOFF
+                                .saturating_mul(1 << (*failed).min(10))
+                                .min(MAX_RETRY_BACKOFF);
+                            *failed += 1;
+                            retries.push((time::Instant::now() + backoff, addr));
+                        }
+                    }
+                }
+                _ = time::sleep_until(wake.unwrap_or_else(time::Instant::now)), if wake.is_some() => {
+                    let now = time::Instant::now();
+                    retries.retain(|&(at, addr)| {
+                        if at > now {
+                            return true;
+                        }
+                        waiting.push_back(addr);
+                        false
+                    });
+                }
+            }
+        }
+        tasks.shutdown().await;
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn is_complete(&self) -> bool {
+        self.queue.lock().unwrap().done.all()
+    }
+
+    async fn run_peer(&self, addr: SocketAddr, pieces: mpsc::Sender<Piece>) -> Result<()> {
+        let mut peer = Peer::connect_peer(addr, self.torrent.info_hash()).await?;
+        self.queue.lock().unwrap().connected.insert(addr);
+        let result = self.download_from(&mut peer, pieces).await;
+        self.queue.lock().unwrap().connected.remove(&addr);
+        result
+    }
+
+    async fn download_from(&self, peer: &mut Peer, pieces: mpsc::Sender<Piece>) -> Result<()> {
+        peer.track_pieces(self.availability.clone());
+        if peer.supports_extensions() {
+            if let Some(discovered) = &self.discovered {
+                peer.extensions
+                    .register(Box::new(PexHandler::new(discovered.clone())));
+            }
+            peer.send_extended_handshake(ExtendedHandshake {
+                p: Some(6881),
+                ..Default::default()
+            })
+            .await?;
+        }
+        peer.send_message(PeerMessage::Interested).await?;
+
+        let mut pex = PexSender::new();
+        let mut pipeline = Pipeline::new();
+        let mut useless_since = None;
+        while !self.is_complete() {
+            if self.queue.lock().unwrap().banned.contains(&peer.addr) {
+                bail!("banned for sending corrupt data");
+            }
+            self.send_pex(peer, &mut pex).await?;
+
+            // Created first so pieces re-queued meanwhile wake us up
+            let changed = self.changed.notified();
+            let Some(assignment) = self.take_piece(peer) else {
+                // Nothing this peer can give us now: wait until it tells us
+                // more, or pieces come back to the queue. One without any
+                // piece we need is dropped after a while.
+                if self.has_needed_piece(peer) {
+                    useless_since = None;
+                } else {
+                    useless_since.get_or_insert_with(time::Instant::now);
+                }
+                let drop_at = useless_since.map(|since| since + USELESS_PEER_GRACE);
+                tokio::select! {
+                    message = peer.read_raw_message() => {
+                        if let PeerMessage::Extended(id, payload) = message? {
+                            peer.handle_extended(id, &payload).await?;
+                        }
+                    }
+                    _ = changed => {}
+                    _ = time::sleep_until(drop_at.unwrap_or_else(time::Instant::now)), if drop_at.is_some() => {
+                        peer.send_message(PeerMessage::NotInterested).await?;
+                        bail!("has no piece we need");
+                    }
+                }
+                continue;
+            };
+            useless_since = None;
+
+            let download = &assignment.download;
+            let Some(data) = download_blocks(download, peer, &mut pipeline).await? else {
+                continue;
+            };
+            // A corrupt piece goes back to the queue with the assignment
+            if !self.check_piece(download, &data) {
+                continue;
+            }
+            pieces
+                .send((download.index, data))
+                .await
+                .context("nobody collects the pieces anymore")?;
+            assignment.finish();
+        }
+        Ok(())
+    }
+
+    /// Whether a downloaded piece is intact. The peers that sent blocks of a
+    /// corrupt one get a strike, and are banned after [`MAX_HASH_FAILURES`].
+    /// Once the piece passes, comparing its blocks with those of the corrupt
+    /// copies tells which peers sent bad data, and they are banned right away
+    /// while the others are cleared of that strike (smart ban).
+    fn check_piece(&self, download: &PieceDownload, data: &[u8]) -> bool {
+        let block_hashes: Vec<[u8; 20]> = data
+            .chunks(BLOCK_MAX as usize)
+            .map(|block| Sha1::digest(block).into())
+            .collect();
+        let hash: [u8; 20] = Sha1::digest(data).into();
+        let mut queue = self.queue.lock().unwrap();
+        let banned = queue.banned.len();
+        let intact = hash == download.hash;
+
+        if intact {
+            for copy in queue.failed.remove(&download.index).unwrap_or_default() {
+                let guilty: HashSet<SocketAddr> = copy
+                    .iter()
+                    .zip(&block_hashes)
+                    .filter(|((_, sent), good)| sent != *good)
+                    .map(|((from, _), _)| *from)
+                    .collect();
+                let senders: HashSet<SocketAddr> = copy.iter().map(|&(from, _)| from).collect();
+                for peer in senders {
+                    if guilty.contains(&peer) {
+                        queue.banned.insert(peer);
+                    } else if let Some(failures) = queue.failures.get_mut(&peer) {
+                        *failures = failures.saturating_sub(1);
+                    }
+                }
+            }
+        } else {
+            let copy: FailedCopy = download.senders().into_iter().zip(block_hashes).collect();
+            let senders: HashSet<SocketAddr> = copy.iter().map(|&(from, _)| from).collect();
+            for peer in senders {
+                let failures = queue.failures.entry(peer).or_default();
+                *failures += 1;
+                if *failures >= MAX_HASH_FAILURES {
+                    queue.banned.insert(peer);
+                }
+            }
+            queue.failed.entry(download.index).or_default().push(copy);
+        }
+
+        // Idle connections of banned peers have to stop
+        if queue.banned.len() > banned {
+            self.changed.notify_waiters();
+        }
+        intact
+    }
+
+    /// Assigns the peer a piece: the next one off the queue if it has any,
+    /// else in endgame one of those in progress, the fewest connections
+    /// work on first.
+    fn take_piece(&self, peer: &Peer) -> Option<Assignment<'_>> {
+        let mut queue = self.queue.lock().unwrap();
+        let download = match self.pick_pending(&mut queue, peer) {
+            Some(position) => {
+                let index = queue.pending.remove(position)?;
+                if queue.pending.is_empty() {
+                    // Idle connections may join in endgame now
+                    self.changed.notify_waiters();
+                }
+                let download = Arc::new(PieceDownload::new(&self.torrent.info, index));
+                queue.active.insert(
+                    index,
+                    Active {
+                        download: download.clone(),
+                        connections: 1,
+                    },
+                );
+                download
+            }
+            None if queue.pending.is_empty() => {
+                let index = queue
+                    .active
+                    .values()
+                    .filter(|active| {
+                        self.may_download(&queue, peer, active.download.index)
+                            && !active.download.is_taken()
+                    })
+                    .min_by_key(|active| active.connections)?
+                    .download
+                    .index;
+                let active = queue.active.get_mut(&index)?;
+                active.connections += 1;
+                active.download.clone()
+            }
+            None => return None,
+        };
+        Some(Assignment {
+            shared: self,
+            download,
+            finished: false,
+        })
+    }
+
+    /// Position in the queue of the next piece for the peer: one it suggested
+    /// if it is unchoking us, else the picker's choice among those it has.
+    /// While choked, only its Allowed Fast pieces can be downloaded.
+    fn pick_pending(&self, queue: &mut Queue, peer: &Peer) -> Option<usize> {
+        let remote = &peer.remote;
+        let suggested = remote.suggested.iter().find_map(|&s| {
+            queue
+                .pending
+                .iter()
+                .position(|&i| i == s as usize && self.may_download(queue, peer, i))
+        });
+        match suggested {
+            Some(position) if !remote.choking => Some(position),
+            _ => {
+                let candidates: Vec<usize> = queue
+                    .pending
+                    .iter()
+                    .copied()
+                    .filter(|&i| self.may_download(queue, peer, i))
+                    .collect();
+                let availability = self.availability.lock().unwrap();
+                let index = queue.picker.pick(&candidates, &availability, &queue.done)?;
+                queue.pending.iter().position(|&i| i == index)
+            }
+        }
+    }
+
+    /// Whether the peer has any piece we still miss, whether or not it
+    /// serves it now.
+    fn has_needed_piece(&self, peer: &Peer) -> bool {
+        let queue = self.queue.lock().unwrap();
+        (0..queue.done.len()).any(|index| !queue.done.get(index) && peer.has_piece(index))
+    }
+
+    /// Whether the peer can send us the piece now. One that sent part of a
+    /// corrupt copy is left out while other connected peers have the piece.
+    fn may_download(&self, queue: &Queue, peer: &Peer, index: usize) -> bool {
+        peer.has_piece(index)
+            && may_request(peer, index)
+            && !(queue.sent_corrupt(index, peer.addr)
+                && self.availability.lock().unwrap().count(index) > 1)
+    }
+
+    async fn send_pex(&self, peer: &mut Peer, pex: &mut PexSender) -> Result<()> {
+        if self.discovered.is_none() || peer.extensions.remote_id("ut_pex").is_none() {
+            return Ok(());
+        }
+        let connected = self.queue.lock().unwrap().connected.clone();
+        if let Some(message) = pex.message(&connected, peer.addr) {
+            peer.send_extended("ut_pex", &message).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the peer currently serves requests for the piece.
+pub fn may_request(peer: &Peer, piece_index: usize) -> bool {
+    !peer.remote.choking || peer.remote.allowed_fast.contains(&(piece_index as u32))
+}
+
+/// Requests a peer may queue when its extended handshake doesn't say (BEP 10)
+const DEFAULT_REQQ: usize = 250;
+const MIN_DEPTH: usize = 2;
+const INITIAL_DEPTH: usize = 4;
+/// Weight of a new sample in the smoothed rate and latency
+const SMOOTHING: f64 = 0.125;
+
+/// How many requests to keep in flight with a peer. Enough to cover what the
+/// peer can send during a round trip, as measured on the blocks received,
+/// so the connection never waits on us.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    depth: usize,
+    /// Smoothed download rate, in bytes per second
+    rate: f64,
+    /// Shortest time a request took to be answered, the round trip without queueing
+    min_latency: Option<Duration>,
+    last_block: Option<Instant>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self {
+            depth: INITIAL_DEPTH,
+            rate: 0.0,
+            min_latency: None,
+            last_block: None,
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests to keep in flight, within what the peer accepts.
+    pub fn depth(&self, peer: &Peer) -> usize {
+        let reqq = peer
+            .extensions
+            .remote()
+            .and_then(|remote| remote.reqq)
+            .map_or(DEFAULT_REQQ, |reqq| reqq as usize);
+        self.depth.min(reqq.max(1))
+    }
+
+    /// Accounts for a request rejected with `in_flight` others still queued.
+    fn on_reject(&mut self, in_flight: usize) {
+        self.depth = self.depth.min(in_flight.max(MIN_DEPTH));
+    }
+
+    /// Accounts for a block of `len` bytes answered `latency` after its request.
+    fn on_block(&mut self, len: usize, latency: Duration) {
+        let now = Instant::now();
+        if let Some(last) = self.last_block.replace(now) {
+            let elapsed = now.duration_since(last).as_secs_f64().max(1e-3);
+            let sample = len as f64 / elapsed;
+            self.rate += SMOOTHING * (sample - self.rate);
+        }
+        let min_latency = self.min_latency.map_or(latency, |min| min.min(latency));
+        self.min_latency = Some(min_latency);
+
+        // Twice the bandwidth-delay product, in blocks
+        let in_flight = self.rate * min_latency.as_secs_f64() / BLOCK_MAX as f64;
+        self.depth = ((2.0 * in_flight).ceil() as usize).clamp(MIN_DEPTH, DEFAULT_REQQ);
+    }
+}
+
+/// A piece being downloaded, shared by the connections working on it: a
+/// single one until endgame.
+struct PieceDownload {
+    index: usize,
+    size: usize,
+    hash: [u8; 20],
+    blocks: Mutex<Blocks>,
+    /// Signalled when a block arrives
+    arrived: Notify,
+}
+
+struct Blocks {
+    data: Vec<u8>,
+    received: Bitfield,
+    /// The peer each block came from
+    senders: Vec<Option<SocketAddr>>,
+    /// Requests in flight for each block, over all connections
+    requests: Vec<usize>,
+    /// Set once a connection took the complete data
+    taken: bool,
 }
 
-struct Part {
+impl PieceDownload {
+    fn new(info: &Info, index: usize) -> Self {
+        let size = info.piece_size(index);
+        let count = size.div_ceil(BLOCK_MAX as usize);
+        Self {
+            index,
+            size,
+            hash: info.pieces.0[index],
+            blocks: Mutex::new(Blocks {
+                data: vec![0; size],
+                received: Bitfield::new(count),
+                senders: vec![None; count],
+                requests: vec![0; count],
+                taken: false,
+            }),
+            arrived: Notify::new(),
+        }
+    }
+
+    fn block(&self, i: usize) -> BlockInfo {
+        let begin = i * BLOCK_MAX as usize;
+        BlockInfo {
+            index: self.index as u32,
+            begin: begin as u32,
+            length: BLOCK_MAX.min((self.size - begin) as u32),
+        }
+    }
+
+    /// The block to request next, out of those missing that we aren't
+    /// waiting for already: one nobody requested if any is left, else the
+    /// least requested, which only happens with several connections in endgame.
+    fn next_block(&self, ours: &HashMap<BlockInfo, Instant>) -> Option<BlockInfo> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let i = (0..blocks.requests.len())
+            .filter(|&i| !blocks.received.get(i) && !ours.contains_key(&self.block(i)))
+            .min_by_key(|&i| blocks.requests[i])?;
+        blocks.requests[i] += 1;
+        Some(self.block(i))
+    }
+
+    /// Forgets a request that was answered or cancelled.
+    fn release(&self, block: &BlockInfo) {
+        let mut blocks = self.blocks.lock().unwrap();
+        let i = (block.begin / BLOCK_MAX) as usize;
+        blocks.requests[i] = blocks.requests[i].saturating_sub(1);
+    }
+
+    fn is_received(&self, block: &BlockInfo) -> bool {
+        let blocks = self.blocks.lock().unwrap();
+        blocks.received.get((block.begin / BLOCK_MAX) as usize)
+    }
+
+    fn is_taken(&self) -> bool {
+        self.blocks.lock().unwrap().taken
+    }
+
+    /// The peer each block came from, once all arrived.
+    fn senders(&self) -> Vec<SocketAddr> {
+        self.blocks
+            .lock()
+            .unwrap()
+            .senders
+            .iter()
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Stores a block `from` a peer, unless another copy of it arrived first.
+    /// The connection completing the piece gets its data.
+    fn store(&self, block: &BlockInfo, bytes: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let i = (block.begin / BLOCK_MAX) as usize;
+        if blocks.received.get(i) {
+            return None;
+        }
+        let begin = block.begin as usize;
+        blocks.data[begin..begin + bytes.len()].copy_from_slice(bytes);
+        blocks.received.set(i, true);
+        blocks.senders[i] = Some(from);
+        self.arrived.notify_waiters();
+        if !blocks.received.all() || blocks.taken {
+            return None;
+        }
+        blocks.taken = true;
+        Some(std::mem::take(&mut blocks.data))
+    }
+}
+
+/// Our requests in flight for a piece, released when we stop waiting for them.
+struct Requests<'a> {
+    download: &'a PieceDownload,
+    sent: HashMap<BlockInfo, Instant>,
+}
+
+impl Requests<'_> {
+    fn remove(&mut self, block: &BlockInfo) -> Option<Instant> {
+        let requested = self.sent.remove(block)?;
+        self.download.release(block);
+        Some(requested)
+    }
+}
+
+impl Drop for Requests<'_> {
+    fn drop(&mut self) {
+        self.sent
+            .keys()
+            .for_each(|block| self.download.release(block));
+    }
+}
+
+/// Downloads a piece and checks its hash, keeping up to the pipeline depth of
+/// block requests in flight. Blocks are matched to their request whatever the
+/// order they arrive in, and rejected blocks are requested again. `None` when
+/// the peer choked us: the piece has to be requested again once the peer lets us.
+pub async fn request_piece(
+    info: &Info,
     piece_index: usize,
+    peer: &mut Peer,
+    pipeline: &mut Pipeline,
+) -> Result<Option<Vec<u8>>> {
+    let download = PieceDownload::new(info, piece_index);
+    let Some(data) = download_blocks(&download, peer, pipeline).await? else {
+        return Ok(None);
+    };
+    let hash: [u8; 20] = Sha1::digest(&data).into();
+    if hash != download.hash {
+        bail!("piece {} failed the hash check", piece_index);
+    }
+    Ok(Some(data))
+}
+
+/// [`request_piece`] for a piece other connections may work on too, without
+/// the hash check. Blocks they receive first are cancelled, and `None` is
+/// also returned to all but the connection completing the piece.
+async fn download_blocks(
+    download: &PieceDownload,
+    peer: &mut Peer,
+    pipeline: &mut Pipeline,
+) -> Result<Option<Vec<u8>>> {
+    let piece_index = download.index;
+    let mut requests = Requests {
+        download,
+        sent: HashMap::new(),
+    };
+
+    loop {
+        // Created first so blocks arriving meanwhile wake us up
+        let arrived = download.arrived.notified();
+        let copies: Vec<BlockInfo> = requests
+            .sent
+            .keys()
+            .filter(|block| download.is_received(block))
+            .copied()
+            .collect();
+        for block in copies {
+            requests.remove(&block);
+            peer.send_message(PeerMessage::Cancel(block)).await?;
+        }
+        if download.is_taken() {
+            return Ok(None);
+        }
+
+        while requests.sent.len() < pipeline.depth(peer) && may_request(peer, piece_index) {
+            let Some(block) = download.next_block(&requests.sent) else {
+                break;
+            };
+            peer.send_message(PeerMessage::Request(block)).await?;
+            requests.sent.insert(block, Instant::now());
+        }
+        if requests.sent.is_empty() {
+            return Ok(None);
+        }
+
+        let message = tokio::select! {
+            message = peer.read_raw_message() => message?,
+            _ = arrived => continue,
+        };
+        match message {
+            PeerMessage::Piece(block, bytes) => {
+                // Late copies of cancelled blocks are dropped here
+                let Some(requested) = requests.remove(&block) else {
+                    continue;
+                };
+                pipeline.on_block(bytes.len(), requested.elapsed());
+                if let Some(data) = download.store(&block, &bytes, peer.addr) {
+                    return Ok(Some(data));
+                }
+            }
+            // The block is requested again, once unchoked if that's why.
+            // Otherwise the peer's queue is full: keep fewer requests in flight.
+            PeerMessage::Reject(block) => {
+                if requests.remove(&block).is_none() {
+                    continue;
+                }
+                pipeline.on_reject(requests.sent.len());
+            }
+            // Without the fast extension a choke drops our pending requests
+            PeerMessage::Choke if !peer.supports_fast() => return Ok(None),
+            PeerMessage::Extended(id, payload) => peer.handle_extended(id, &payload).await?,
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn pipeline_follows_bandwidth_delay_product() {
+    let mut pipeline = Pipeline::new();
+    assert_eq!(INITIAL_DEPTH, pipeline.depth);
+
+    // 1.6 MB/s with a 100ms round trip keeps ten blocks in flight, twice that with headroom
+    pipeline.rate = 1_638_400.0;
+    pipeline.min_latency = Some(Duration::from_millis(100));
+    pipeline.last_block = Some(Instant::now() - Duration::from_millis(10));
+    pipeline.on_block(16384, Duration::from_millis(300));
+    assert_eq!(Some(Duration::from_millis(100)), pipeline.min_latency);
+    assert!((18..=22).contains(&pipeline.depth), "{}", pipeline.depth);
+
+    // A slow peer close by needs little
+    pipeline.rate = 10_000.0;
+    pipeline.min_latency = Some(Duration::from_millis(5));
+    pipeline.last_block = Some(Instant::now() - Duration::from_secs(2));
+    pipeline.on_block(16384, Duration::from_millis(5));
+    assert_eq!(MIN_DEPTH, pipeline.depth);
+}
+
+/// A local seeder serving the pieces in `has`, choking us for good after
+/// serving `serve` blocks. Requests arriving together are answered in reverse
+/// order. With `reject`, it supports the fast extension and rejects that many
+/// requests first. With `report`, it sends there the messages it gets and
+/// ignores requests past `serve` instead of choking.
+#[cfg(test)]
+async fn fake_seeder(
+    data: Arc<Vec<u8>>,
+    plength: usize,
+    has: Bitfield,
+    serve: usize,
+    reject: usize,
+    report: Option<mpsc::UnboundedSender<PeerMessage>>,
+) -> SocketAddr {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    use crate::peer::{HandshakeCodec, PeerCapabilities, PeerCodec};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(stream, HandshakeCodec);
+        let mut handshake = framed.next().await.unwrap().unwrap();
+        handshake.reserved_bytes = PeerCapabilities {
+            fast: reject > 0,
+            ..Default::default()
+        }
+        .to_reserved();
+        framed.send(handshake).await.unwrap();
+
+        let mut framed = framed.map_codec(|_| PeerCodec::default());
+        framed.send(PeerMessage::Bitfield(has)).await.unwrap();
+        framed.send(PeerMessage::Unchoke).await.unwrap();
+        let mut served = 0;
+        let mut rejected = 0;
+        let mut batch = Vec::new();
+        loop {
+            let next = if batch.is_empty() {
+                Ok(framed.next().await)
+            } else {
+                tokio::time::timeout(Duration::from_millis(20), framed.next()).await
+            };
+            if let (Ok(Some(Ok(message))), Some(report)) = (&next, &report) {
+                report.send(message.clone()).unwrap();
+            }
+            match next {
+                Ok(Some(Ok(PeerMessage::Request(block)))) => batch.push(block),
+                Ok(Some(Ok(_))) => {}
+                Ok(_) => return,
+                // Answer the requests that came in together, last one first
+                Err(_) => {
+                    for block in batch.drain(..).rev() {
+                        if rejected < reject {
+                            rejected += 1;
+                            framed.send(PeerMessage::Reject(block)).await.unwrap();
+                            continue;
+                        }
+                        if served == serve {
+                            if report.is_none() {
+                                framed.send(PeerMessage::Choke).await.unwrap();
+                            }
+                            continue;
+                        }
+                        served += 1;
+                        let start = block.index as usize * plength + block.begin as usize;
+                        let data = data[start..start + block.length as usize].to_vec();
+                        framed
+                            .send(PeerMessage::Piece(block, data.into()))
+                            .await
+                            .unwrap();
+                    }
+                }
+            }
+        }
+    });
+    addr
+}
+
+/// A single file torrent of `data`.
+#[cfg(test)]
+fn test_torrent(data: &[u8], plength: usize) -> Arc<Torrent> {
+    use std::collections::BTreeMap;
+
+    use crate::BencodeValue;
+
+    let hashes: Vec<u8> = data
+        .chunks(plength)
+        .flat_map(|piece| <[u8; 20]>::from(Sha1::digest(piece)))
+        .collect();
+    let mut info = BTreeMap::new();
+    info.insert(b"length".to_vec(), (data.len() as i64).into());
+    info.insert(b"name".to_vec(), "test".into());
+    info.insert(b"piece length".to_vec(), (plength as i64).into());
+    info.insert(b"pieces".to_vec(), hashes.into());
+    let info = BencodeValue::Dict(info).encode();
+    Arc::new(Torrent::from_info_bytes(&info, &[]).unwrap())
+}
+
+#[tokio::test]
+async fn downloads_from_several_peers() {
+    let plength = 32768;
+    let data: Vec<u8> = (0..5 * plength + 1000).map(|i| (i % 251) as u8).collect();
+    let torrent = test_torrent(&data, plength);
+    let num_pieces = torrent.info.num_pieces();
+    let data = Arc::new(data);
+
+    // One seeder only has the even pieces, another chokes us after a few
+    // blocks, leaving the piece it was on to be re-queued
+    let mut even = Bitfield::new(num_pieces);
+    (0..num_pieces).step_by(2).for_each(|i| even.set(i, true));
+    let full = Bitfield::full(num_pieces);
+    let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+    for (has, serve) in [(even, usize::MAX), (full.clone(), 3), (full, usize::MAX)] {
+        let seeder = fake_seeder(data.clone(), plength, has, serve, 0, None).await;
+        peers_tx.send(seeder).unwrap();
+    }
+
+    let (pieces_tx, mut pieces_rx) = mpsc::channel(num_pieces);
+    let worker = tokio::spawn(Worker::new(torrent).run(peers_rx, pieces_tx));
+    let mut received = vec![Vec::new(); num_pieces];
+    while let Some((index, piece)) = pieces_rx.recv().await {
+        received[index] = piece;
+    }
+    worker.await.unwrap().unwrap();
+    assert_eq!(*data, received.concat());
+}
+
+#[tokio::test]
+async fn endgame_duplicates_requests_of_stalled_peer() {
+    let plength = 4 * BLOCK_MAX as usize;
+    let data: Vec<u8> = (0..plength).map(|i| (i % 251) as u8).collect();
+    let torrent = test_torrent(&data, plength);
+    let data = Arc::new(data);
+    let full = Bitfield::full(1);
+
+    // The only piece goes to a seeder that never answers
+    let (report_tx, mut report) = mpsc::unbounded_channel();
+    let stalled = fake_seeder(data.clone(), plength, full.clone(), 0, 0, Some(report_tx)).await;
+    let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+    peers_tx.send(stalled).unwrap();
+    let (pieces_tx, mut pieces_rx) = mpsc::channel(1);
+    let worker = tokio::spawn(Worker::new(torrent).run(peers_rx, pieces_tx));
+    let mut requested = HashSet::new();
+    while requested.len() < 4 {
+        if let PeerMessage::Request(block) = report.recv().await.unwrap() {
+            requested.insert(block);
+        }
+    }
+
+    let seeder = fake_seeder(data.clone(), plength, full, usize::MAX, 0, None).await;
+    peers_tx.send(seeder).unwrap();
+    let (index, piece) = pieces_rx.recv().await.unwrap();
+    assert_eq!((0, &*data), (index, &piece));
+    assert!(pieces_rx.recv().await.is_none());
+    worker.await.unwrap().unwrap();
+
+    // Every block the stalled seeder still owed was cancelled
+    let mut cancelled = HashSet::new();
+    while let Some(message) = report.recv().await {
+        match message {
+            PeerMessage::Cancel(block) => assert!(cancelled.insert(block)),
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+    assert_eq!(requested, cancelled);
+}
+
+#[tokio::test]
+async fn bans_peer_sending_corrupt_data() {
+    let plength = 2 * BLOCK_MAX as usize;
+    let data: Vec<u8> = (0..4 * plength).map(|i| (i % 251) as u8).collect();
+    let torrent = test_torrent(&data, plength);
+    let full = Bitfield::full(4);
+
+    // The first peer sends every block flipped, so no piece it sends passes
+    let corrupt = Arc::new(data.iter().map(|b| !b).collect());
+    let (report_tx, mut report) = mpsc::unbounded_channel();
+    let bad = fake_seeder(
+        corrupt,
+        plength,
+        full.clone(),
+        usize::MAX,
+        0,
+        Some(report_tx),
+    )
+    .await;
+    let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+    peers_tx.send(bad).unwrap();
+    let worker = Worker::new(torrent);
+    let shared = worker.shared.clone();
+    let (pieces_tx, mut pieces_rx) = mpsc::channel(4);
+    let worker = tokio::spawn(worker.run(peers_rx, pieces_tx));
+    while !matches!(report.recv().await, Some(PeerMessage::Request(_))) {}
+
+    let data = Arc::new(data);
+    let good = fake_seeder(data.clone(), plength, full, usize::MAX, 0, None).await;
+    peers_tx.send(good).unwrap();
+    let mut received = vec![Vec::new(); 4];
+    while let Some((index, piece)) = pieces_rx.recv().await {
+        received[index] = piece;
+    }
+    worker.await.unwrap().unwrap();
+    assert_eq!(*data, received.concat());
+
+    let queue = shared.queue.lock().unwrap();
+    assert_eq!(HashSet::from([bad]), queue.banned);
+    assert!(queue.failed.is_empty());
+    assert!(!queue.failures.contains_key(&good));
+}
+
+#[tokio::test(start_paused = true)]
+async fn gives_up_once_no_peer_is_left() {
+    let data = vec![0; 1000];
+    let torrent = test_torrent(&data, 1000);
+
+    // Nothing listens there anymore, and nobody else will tell us of peers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let refusing = listener.local_addr().unwrap();
+    drop(listener);
+    let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+    peers_tx.send(refusing).unwrap();
+    drop(peers_tx);
+
+    let (pieces_tx, _pieces_rx) = mpsc::channel(1);
+    let started = time::Instant::now();
+    let error = Worker::new(torrent)
+        .run(peers_rx, pieces_tx)
+        .await
+        .unwrap_err();
+    assert_eq!("no peers left to download from", error.to_string());
+    // The peer was tried again until its backoff outgrew the grace period
+    assert!(started.elapsed() > NO_PEERS_GRACE);
+}
+
+#[tokio::test]
+async fn rejected_blocks_are_requested_again() {
+    let plength = 4 * BLOCK_MAX as usize;
+    let data: Vec<u8> = (0..2 * plength).map(|i| (i % 251) as u8).collect();
+    let torrent = test_torrent(&data, plength);
+    let data = Arc::new(data);
+
+    // An unchoking peer rejecting requests, as it does with a full queue
+    let seeder = fake_seeder(
+        data.clone(),
+        plength,
+        Bitfield::full(2),
+        usize::MAX,
+        3,
+        None,
+    )
+    .await;
+    let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+    peers_tx.send(seeder).unwrap();
+    let (pieces_tx, mut pieces_rx) = mpsc::channel(2);
+    let worker = tokio::spawn(Worker::new(torrent).run(peers_rx, pieces_tx));
+    let mut received = vec![Vec::new(); 2];
+    while let Some((index, piece)) = pieces_rx.recv().await {
+        received[index] = piece;
+    }
+    worker.await.unwrap().unwrap();
+    assert_eq!(*data, received.concat());
+}
+
+#[tokio::test(start_paused = true)]
+async fn peers_without_needed_pieces_are_dropped() {
+    let data = vec![0; 1000];
+    let torrent = test_torrent(&data, 1000);
+
+    let (report_tx, mut report_rx) = mpsc::unbounded_channel();
+    let empty = fake_seeder(
+        Arc::new(data),
+        1000,
+        Bitfield::new(1),
+        0,
+        0,
+        Some(report_tx),
+    )
+    .await;
+    let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+    peers_tx.send(empty).unwrap();
+
+    let (pieces_tx, _pieces_rx) = mpsc::channel(1);
+    let worker = tokio::spawn(Worker::new(torrent).run(peers_rx, pieces_tx));
+    let started = time::Instant::now();
+    loop {
+        match report_rx.recv().await {
+            Some(PeerMessage::NotInterested) => break,
+            Some(_) => {}
+            None => panic!("the peer was never told we are not interested"),
+        }
+    }
+    assert!(started.elapsed() >= USELESS_PEER_GRACE);
+    worker.abort();
 }